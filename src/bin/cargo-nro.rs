@@ -11,6 +11,7 @@ extern crate goblin;
 extern crate scroll;
 
 use scroll::IOwrite;
+use std::collections::BTreeMap;
 use std::env::{self, VarError};
 use std::fs::File;
 use std::io::{Read, Write};
@@ -22,7 +23,11 @@ use cargo_toml2::CargoConfig;
 use clap::{App, Arg};
 use goblin::elf::section_header::{SHT_NOBITS, SHT_STRTAB, SHT_SYMTAB};
 use goblin::elf::{Elf, Header as ElfHeader, ProgramHeader};
-use linkle::format::{nacp::NacpFile, nxo::NxoFile, romfs::RomFs};
+use linkle::format::{
+    nacp::NacpFile,
+    nxo::{NroAsset, NroAssets, NroOptions, NxoFile},
+    romfs::RomFs,
+};
 use snafu::Snafu;
 
 #[derive(Debug, Snafu)]
@@ -217,6 +222,8 @@ struct PackageMetadata {
     nacp: Option<NacpFile>,
     icon: Option<String>,
     title_id: Option<String>,
+    #[serde(default)]
+    embedded_files: BTreeMap<String, String>,
 }
 
 trait WorkspaceMember {
@@ -349,9 +356,6 @@ fn main() {
                     None
                 };
 
-                let icon_file = icon_file.map(|v| v.to_string_lossy().into_owned());
-                let icon_file = icon_file.as_ref().map(|v| v.as_ref());
-
                 let mut nacp = target_metadata.nacp.unwrap_or_default();
                 nacp.name.get_or_insert(package.name.clone());
                 nacp.author.get_or_insert(package.authors[0].clone());
@@ -363,6 +367,24 @@ fn main() {
                 let romfs =
                     generate_debuginfo_romfs(Path::new(&artifact.filenames[0]), romfs).unwrap();
 
+                let mut files = BTreeMap::new();
+                for (name, path) in target_metadata.embedded_files {
+                    let file_path = root.join(path);
+                    if !file_path.is_file() {
+                        panic!("Invalid embedded file {:?}", file_path);
+                    }
+                    let file = NroAsset::from_reader(File::open(file_path).unwrap());
+                    files.insert(name, file);
+                }
+
+                let mut assets = NroAssets {
+                    icon: icon_file
+                        .map(|v| NroAsset::icon_from_reader(File::open(v).unwrap()).unwrap()),
+                    nacp: Some(NroAsset::from(nacp)),
+                    romfs: Some(NroAsset::from(romfs)),
+                    files,
+                };
+
                 let mut new_name = artifact.filenames[0].clone();
                 assert!(new_name.set_extension("nro"));
 
@@ -370,9 +392,8 @@ fn main() {
                     .unwrap()
                     .write_nro(
                         &mut File::create(new_name.clone()).unwrap(),
-                        Some(romfs),
-                        icon_file,
-                        Some(nacp),
+                        &mut assets,
+                        &NroOptions::default(),
                     )
                     .unwrap();
 
@@ -3,11 +3,19 @@ extern crate structopt;
 extern crate linkle;
 
 use linkle::error::ResultExt;
+use linkle::format::nxo::{
+    take_embedded_files, NroAsset, NroAssets, NroOptions, NsoCompression, NsoOptions,
+    NsoSegmentOptions,
+};
+use std::collections::BTreeMap;
 use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 use structopt::StructOpt;
 
+const COMPRESSIONS: &[&str] = &["none", "lz4", "lz4hc"];
+
 #[derive(StructOpt)]
 #[structopt(name = "linkle", about = "The legendary hero")]
 enum Opt {
@@ -31,6 +39,55 @@ enum Opt {
         /// Sets the NACP JSON to use when bundling into an NRO.
         #[structopt(long = "nacp-path")]
         nacp: Option<String>,
+
+        /// Embeds an arbitrary file in the NRO, as NAME=PATH. It is stored
+        /// in the embedded directory of the RomFS.
+        #[structopt(long = "embed", parse(try_from_str = parse_embedded_file))]
+        embedded_files: Vec<(String, String)>,
+
+        /// Sets the version field of the NRO header.
+        #[structopt(long = "nro-version", default_value = "0", parse(try_from_str = parse_u32))]
+        version: u32,
+
+        /// Sets the flags field of the NRO header.
+        #[structopt(long = "nro-flags", default_value = "0", parse(try_from_str = parse_u32))]
+        flags: u32,
+    },
+    /// Replace or remove the assets of an existing NRO file.
+    #[structopt(name = "nro_assets")]
+    NroAssets {
+        /// Sets the input NRO to use.
+        input_file: String,
+
+        /// Sets the output file to use. May be the same as the input file.
+        output_file: String,
+
+        /// Replaces the icon with this one.
+        #[structopt(long = "icon-path")]
+        icon: Option<String>,
+
+        /// Replaces the RomFs with this directory. The embedded files are
+        /// kept.
+        #[structopt(long = "romfs-path")]
+        romfs: Option<String>,
+
+        /// Replaces the NACP with this NACP JSON.
+        #[structopt(long = "nacp-path")]
+        nacp: Option<String>,
+
+        /// Removes an asset from the NRO. Removing the RomFS keeps the
+        /// embedded files.
+        #[structopt(long = "remove", possible_values = &["icon", "nacp", "romfs"])]
+        remove: Vec<String>,
+
+        /// Embeds an arbitrary file in the NRO, as NAME=PATH. Replaces any
+        /// embedded file with the same name.
+        #[structopt(long = "embed", parse(try_from_str = parse_embedded_file))]
+        embedded_files: Vec<(String, String)>,
+
+        /// Removes the embedded file with this name from the NRO.
+        #[structopt(long = "remove-embedded")]
+        remove_embedded: Vec<String>,
     },
     /// Create a NSO file from an ELF file.
     #[structopt(name = "nso")]
@@ -39,6 +96,38 @@ enum Opt {
         input_file: String,
         /// Sets the output file to use.
         output_file: String,
+        /// Sets the module path stored at the start of .rodata. The ELF must
+        /// reserve room for it there. Defaults to the input file name, without
+        /// its extension.
+        #[structopt(long = "module-name")]
+        module_name: Option<String>,
+        /// Sets the compression of all segments.
+        #[structopt(long = "compression", default_value = "lz4", possible_values = COMPRESSIONS)]
+        compression: NsoCompression,
+        /// Sets the compression of the .text segment, overriding --compression.
+        #[structopt(long = "text-compression", possible_values = COMPRESSIONS)]
+        text_compression: Option<NsoCompression>,
+        /// Sets the compression of the .rodata segment, overriding --compression.
+        #[structopt(long = "rodata-compression", possible_values = COMPRESSIONS)]
+        rodata_compression: Option<NsoCompression>,
+        /// Sets the compression of the .data segment, overriding --compression.
+        #[structopt(long = "data-compression", possible_values = COMPRESSIONS)]
+        data_compression: Option<NsoCompression>,
+        /// Disables the hash check of a segment when it is loaded.
+        #[structopt(long = "no-hash-check", possible_values = &["text", "rodata", "data"])]
+        no_hash_check: Vec<String>,
+    },
+    /// Decompress a NSO file into an ELF, or into its raw segments.
+    #[structopt(name = "nso_extract")]
+    NsoExtract {
+        /// Sets the input NSO to use.
+        input_file: String,
+        /// Sets the output ELF file to use, or the output directory with --raw.
+        output: String,
+        /// Write the decompressed segments as text.bin, rodata.bin and
+        /// data.bin instead of building an ELF.
+        #[structopt(long = "raw")]
+        raw: bool,
     },
     /// Create a KIP file from an ELF and an NPDM file.
     #[structopt(name = "kip")]
@@ -50,6 +139,12 @@ enum Opt {
         /// Sets the output file to use.
         output_file: String,
     },
+    /// Print the NPDM JSON of a KIP file, in the format used by the kip subcommand.
+    #[structopt(name = "kip_info")]
+    KipInfo {
+        /// Sets the input KIP to use.
+        input_file: String,
+    },
     /// Create a PFS0 or NSP file from a directory.
     #[structopt(name = "pfs0"/*, raw(alias = "nsp")*/)]
     Pfs0 {
@@ -66,6 +161,20 @@ enum Opt {
         /// Sets the output directory to extract the PFS0 into.
         output_directory: String,
     },
+    /// Extract the icon, NACP, RomFS and embedded files of a NRO file.
+    #[structopt(name = "nro_extract")]
+    NroExtract {
+        /// Sets the input NRO to use.
+        input_file: String,
+        /// Sets the output directory to extract the assets into.
+        output_directory: String,
+        /// Also write the NACP as JSON, in the format used by the nacp subcommand.
+        #[structopt(long = "nacp-json")]
+        nacp_json: bool,
+        /// Extract the RomFS as a directory tree instead of a raw image.
+        #[structopt(long = "romfs-dir")]
+        romfs_dir: bool,
+    },
     /// Create a NACP file from a JSON file.
     #[structopt(name = "nacp")]
     Nacp {
@@ -74,6 +183,55 @@ enum Opt {
         /// Sets the output file to use.
         output_file: String,
     },
+    /// Print the JSON description of a NACP file, in the format used by the nacp subcommand.
+    #[structopt(name = "nacp_extract")]
+    NacpExtract {
+        /// Sets the input NACP to use.
+        input_file: String,
+        /// Writes the JSON to this file instead of printing it.
+        output_file: Option<String>,
+    },
+    /// Create an NPDM file from a JSON-NPDM formatted file.
+    #[structopt(name = "npdm")]
+    Npdm {
+        /// Sets the input file to use.
+        input_file: String,
+        /// Sets the output file to use.
+        output_file: String,
+        /// Signs the ACID with this RSA-2048 private key (PEM or DER).
+        #[structopt(long = "acid-key")]
+        acid_key: Option<String>,
+        /// Also replaces the ACID modulus with the one of the signing key.
+        #[structopt(long = "fake-sign", requires = "acid-key")]
+        fake_sign: bool,
+    },
+    /// Print the JSON-NPDM description of an NPDM file.
+    #[structopt(name = "npdm_info")]
+    NpdmInfo {
+        /// Sets the input NPDM to use.
+        input_file: String,
+    },
+    /// Check the ACID signature of an NPDM file against the fixed keys, or
+    /// against the key it was signed with.
+    #[structopt(name = "npdm_verify")]
+    NpdmVerify {
+        /// Sets the input NPDM to use.
+        input_file: String,
+
+        /// Checks the signature against the modulus of this RSA-2048 private
+        /// key (PEM or DER), such as a fake-signing key, instead of the fixed
+        /// keys.
+        #[structopt(long = "acid-key")]
+        acid_key: Option<String>,
+
+        /// Use development keys instead of retail
+        #[structopt(short = "d", long = "dev")]
+        dev: bool,
+
+        /// Key file to use
+        #[structopt(parse(from_os_str), short = "k", long = "keyset")]
+        keyfile: Option<PathBuf>,
+    },
     /// Create a RomFS file from a directory.
     #[structopt(name = "romfs")]
     Romfs {
@@ -105,13 +263,34 @@ enum Opt {
     },
 }
 
-fn create_nxo(
-    format: &str,
+fn parse_embedded_file(arg: &str) -> Result<(String, String), String> {
+    match arg.find('=') {
+        Some(idx) => Ok((arg[..idx].to_string(), arg[idx + 1..].to_string())),
+        None => Err(format!("expected NAME=PATH, got {}", arg)),
+    }
+}
+
+fn embedded_file_asset(path: &str) -> Result<NroAsset, linkle::error::Error> {
+    let file = File::open(path).map_err(|err| (err, path))?;
+    Ok(NroAsset::from_reader(file))
+}
+
+fn parse_u32(arg: &str) -> Result<u32, std::num::ParseIntError> {
+    if arg.starts_with("0x") {
+        u32::from_str_radix(&arg[2..], 16)
+    } else {
+        arg.parse()
+    }
+}
+
+fn create_nro(
     input_file: &str,
     output_file: &str,
     icon_file: Option<&str>,
     romfs_dir: Option<&str>,
     nacp_file: Option<&str>,
+    embedded_files: &[(String, String)],
+    options: &NroOptions,
 ) -> Result<(), linkle::error::Error> {
     let romfs_dir = if let Some(romfs_path) = romfs_dir {
         Some(linkle::format::romfs::RomFs::from_directory(Path::new(
@@ -128,29 +307,117 @@ fn create_nxo(
     } else {
         None
     };
+    let icon_file = if let Some(icon_path) = icon_file {
+        let icon = File::open(icon_path).map_err(|err| (err, icon_path))?;
+        Some(NroAsset::icon_from_reader(icon).with_path(icon_path)?)
+    } else {
+        None
+    };
+    let mut files = BTreeMap::new();
+    for (name, path) in embedded_files {
+        files.insert(name.clone(), embedded_file_asset(path)?);
+    }
+    let mut assets = NroAssets {
+        icon: icon_file,
+        nacp: nacp_file.map(NroAsset::from),
+        romfs: romfs_dir.map(NroAsset::from),
+        files,
+    };
 
     let mut nxo =
         linkle::format::nxo::NxoFile::from_elf(&input_file).map_err(|err| (err, &input_file))?;
     let mut option = OpenOptions::new();
     let output_option = option.write(true).create(true).truncate(true);
-    match format {
-        "nro" => {
-            let mut out_file = output_option
-                .open(output_file)
-                .map_err(|err| (err, output_file))?;
-            nxo.write_nro(&mut out_file, romfs_dir, icon_file.as_deref(), nacp_file)
-                .map_err(|err| (err, output_file))?;
+    let mut out_file = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    nxo.write_nro(&mut out_file, &mut assets, options)
+        .with_path(output_file)?;
+    Ok(())
+}
+
+fn create_nso(
+    input_file: &str,
+    output_file: &str,
+    options: &NsoOptions,
+) -> Result<(), linkle::error::Error> {
+    let mut nxo =
+        linkle::format::nxo::NxoFile::from_elf(&input_file).map_err(|err| (err, &input_file))?;
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut out_file = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    nxo.write_nso(&mut out_file, options)
+        .map_err(|err| (err, output_file))?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn replace_nro_assets(
+    input_path: &str,
+    output_path: &str,
+    icon_file: Option<&str>,
+    romfs_dir: Option<&str>,
+    nacp_file: Option<&str>,
+    remove: &[String],
+    embedded_files: &[(String, String)],
+    remove_embedded: &[String],
+) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let nro = linkle::format::nxo::NroFile::from_reader(input_file).with_path(input_path)?;
+    let mut assets = nro.assets().with_path(input_path)?;
+
+    if let Some(icon_path) = icon_file {
+        let icon = File::open(icon_path).map_err(|err| (err, icon_path))?;
+        assets.icon = Some(NroAsset::icon_from_reader(icon).with_path(icon_path)?);
+    }
+    if let Some(romfs_path) = romfs_dir {
+        let romfs = linkle::format::romfs::RomFs::from_directory(Path::new(romfs_path))?;
+        assets.romfs = Some(NroAsset::from(romfs));
+    }
+    if let Some(nacp_path) = nacp_file {
+        let nacp =
+            linkle::format::nacp::NacpFile::from_file(nacp_path).map_err(|err| (err, nacp_path))?;
+        assets.nacp = Some(NroAsset::from(nacp));
+    }
+    for asset in remove {
+        match &**asset {
+            "icon" => assets.icon = None,
+            "nacp" => assets.nacp = None,
+            "romfs" => assets.romfs = None,
+            _ => unreachable!(),
         }
-        "nso" => {
-            let mut out_file = output_option
-                .open(output_file)
-                .map_err(|err| (err, output_file))?;
-            nxo.write_nso(&mut out_file)
-                .map_err(|err| (err, output_file))?;
+    }
+    for (name, path) in embedded_files {
+        assets
+            .files
+            .insert(name.clone(), embedded_file_asset(path)?);
+    }
+    for name in remove_embedded {
+        if !assets.remove_embedded_file(name) {
+            println!("Warning: no embedded file named {}", name);
         }
-        _ => process::exit(1),
     }
-    Ok(())
+
+    // Write to a temporary file first, so the input can be overwritten.
+    let tmp_path = format!("{}.tmp", output_path);
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut out_file = output_option
+        .open(&tmp_path)
+        .map_err(|err| (err, &tmp_path))?;
+    let res = nro
+        .write_with_assets(&mut out_file, &mut assets)
+        .with_path(&tmp_path)
+        .and_then(|_| {
+            std::fs::rename(&tmp_path, output_path).map_err(|err| (err, output_path).into())
+        });
+    if res.is_err() {
+        // Don't leave a half-written temporary file behind.
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    res
 }
 
 fn create_kip(
@@ -172,7 +439,15 @@ fn create_kip(
             .map_err(|err| (err, output_file))?,
         &npdm,
     )
-    .map_err(|err| (err, output_file))?;
+    .with_path(output_file)?;
+    Ok(())
+}
+
+fn print_kip_info(input_file: &str) -> Result<(), linkle::error::Error> {
+    let kip_file = File::open(input_file).map_err(|err| (err, input_file))?;
+    let kip = linkle::format::nxo::Kip1File::from_reader(kip_file).with_path(input_file)?;
+    let npdm = linkle::format::nxo::KipNpdm::from_kip(kip.header())?;
+    println!("{}", serde_json::to_string_pretty(&npdm)?);
     Ok(())
 }
 
@@ -210,6 +485,118 @@ fn extract_pfs0(input_path: &str, output_directory: &str) -> Result<(), linkle::
     Ok(())
 }
 
+fn extract_nro(
+    input_path: &str,
+    output_directory: &str,
+    nacp_json: bool,
+    romfs_dir: bool,
+) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let nro = linkle::format::nxo::NroFile::from_reader(input_file).with_path(input_path)?;
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let path = Path::new(output_directory);
+    match std::fs::create_dir(path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err((err, path).into()),
+    }
+
+    if let Some(mut icon) = nro.icon().map_err(|err| (err, input_path))? {
+        let name = path.join("icon.jpg");
+        println!("Writing icon.jpg");
+        let mut out_file = output_option.open(&name).map_err(|err| (err, &name))?;
+        std::io::copy(&mut icon, &mut out_file).map_err(|err| (err, &name))?;
+    }
+
+    if let Some(mut nacp) = nro.nacp().map_err(|err| (err, input_path))? {
+        let name = path.join("control.nacp");
+        println!("Writing control.nacp");
+        let mut out_file = output_option.open(&name).map_err(|err| (err, &name))?;
+        std::io::copy(&mut nacp, &mut out_file).map_err(|err| (err, &name))?;
+
+        if nacp_json {
+            nacp.seek(SeekFrom::Start(0))
+                .map_err(|err| (err, input_path))?;
+            let nacp = linkle::format::nacp::NacpFile::from_reader(nacp).with_path(input_path)?;
+            let name = path.join("control.json");
+            println!("Writing control.json");
+            let out_file = output_option.open(&name).map_err(|err| (err, &name))?;
+            serde_json::to_writer_pretty(out_file, &nacp)?;
+        }
+    }
+
+    // The embedded files are extracted on their own, without the RomFS.
+    let embedded_files = nro.embedded_files().with_path(input_path)?;
+    if let Some(mut romfs) = nro.romfs().map_err(|err| (err, input_path))? {
+        if romfs_dir || !embedded_files.is_empty() {
+            let mut romfs =
+                linkle::format::romfs::RomFs::from_reader(romfs).with_path(input_path)?;
+            take_embedded_files(&mut romfs).map_err(|err| (err, input_path))?;
+            if romfs_dir {
+                romfs.extract_to(&path.join("romfs"))?;
+            } else {
+                let name = path.join("romfs.bin");
+                println!("Writing romfs.bin");
+                let mut out_file = output_option.open(&name).map_err(|err| (err, &name))?;
+                romfs.write(&mut out_file).map_err(|err| (err, &name))?;
+            }
+        } else {
+            let name = path.join("romfs.bin");
+            println!("Writing romfs.bin");
+            let mut out_file = output_option.open(&name).map_err(|err| (err, &name))?;
+            std::io::copy(&mut romfs, &mut out_file).map_err(|err| (err, &name))?;
+        }
+    }
+
+    if !embedded_files.is_empty() {
+        let embedded_path = path.join("embedded");
+        match std::fs::create_dir(&embedded_path) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err((err, embedded_path).into()),
+        }
+        for (file_name, data) in embedded_files {
+            let name = embedded_path.join(&file_name);
+            println!("Writing embedded/{}", file_name);
+            std::fs::write(&name, data).map_err(|err| (err, &name))?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_nso(input_path: &str, output: &str, raw: bool) -> Result<(), linkle::error::Error> {
+    let input_file = File::open(input_path).map_err(|err| (err, input_path))?;
+    let nso = linkle::format::nxo::NsoFile::from_reader(input_file).with_path(input_path)?;
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+
+    if raw {
+        let path = Path::new(output);
+        match std::fs::create_dir(path) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(err) => return Err((err, path).into()),
+        }
+        for (name, data) in &[
+            ("text.bin", nso.text()),
+            ("rodata.bin", nso.rodata()),
+            ("data.bin", nso.data()),
+        ] {
+            let file_path = path.join(name);
+            println!("Writing {}", name);
+            let mut out_file = output_option
+                .open(&file_path)
+                .map_err(|err| (err, &file_path))?;
+            out_file.write_all(data).map_err(|err| (err, &file_path))?;
+        }
+    } else {
+        let mut out_file = output_option.open(output).map_err(|err| (err, output))?;
+        nso.write_elf(&mut out_file).with_path(output)?;
+    }
+    Ok(())
+}
+
 fn create_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error::Error> {
     let mut nacp = linkle::format::nacp::NacpFile::from_file(&input_file)?;
     let mut option = OpenOptions::new();
@@ -222,6 +609,68 @@ fn create_nacp(input_file: &str, output_file: &str) -> Result<(), linkle::error:
     Ok(())
 }
 
+fn extract_nacp(input_file: &str, output_file: Option<&str>) -> Result<(), linkle::error::Error> {
+    let nacp_file = File::open(input_file).map_err(|err| (err, input_file))?;
+    let nacp = linkle::format::nacp::NacpFile::from_reader(nacp_file).with_path(input_file)?;
+    match output_file {
+        Some(output_file) => {
+            let out_file = File::create(output_file).map_err(|err| (err, output_file))?;
+            serde_json::to_writer_pretty(out_file, &nacp)?;
+        }
+        None => println!("{}", serde_json::to_string_pretty(&nacp)?),
+    }
+    Ok(())
+}
+
+fn create_npdm(
+    input_file: &str,
+    output_file: &str,
+    acid_key: Option<&str>,
+    fake_sign: bool,
+) -> Result<(), linkle::error::Error> {
+    let mut npdm = linkle::format::npdm::NpdmFile::from_file(&input_file)?;
+    if let Some(acid_key) = acid_key {
+        let key = linkle::format::npdm::AcidSigningKey::from_file(acid_key)?;
+        npdm.sign_acid(&key, fake_sign)?;
+    }
+    let mut option = OpenOptions::new();
+    let output_option = option.write(true).create(true).truncate(true);
+    let mut out_file = output_option
+        .open(output_file)
+        .map_err(|err| (err, output_file))?;
+    npdm.write(&mut out_file).with_path(output_file)?;
+    Ok(())
+}
+
+fn print_npdm_info(input_file: &str) -> Result<(), linkle::error::Error> {
+    let npdm_file = File::open(input_file).map_err(|err| (err, input_file))?;
+    let npdm = linkle::format::npdm::NpdmFile::from_reader(npdm_file).with_path(input_file)?;
+    println!("{}", serde_json::to_string_pretty(&npdm)?);
+    Ok(())
+}
+
+fn verify_npdm(
+    input_file: &str,
+    acid_key: Option<&str>,
+    is_dev: bool,
+    key_path: Option<&Path>,
+) -> Result<(), linkle::error::Error> {
+    let data = std::fs::read(input_file).map_err(|err| (err, input_file))?;
+    if let Some(acid_key) = acid_key {
+        let key = linkle::format::npdm::AcidSigningKey::from_file(acid_key)?;
+        linkle::format::npdm::NpdmFile::verify_acid_signature_with_modulus(&data, &key.modulus())?;
+    } else {
+        let keys = if is_dev {
+            linkle::pki::Keys::new_dev(key_path)?
+        } else {
+            linkle::pki::Keys::new_retail(key_path)?
+        };
+        linkle::format::npdm::NpdmFile::verify_acid_signature(&data, &keys)?;
+    }
+    println!("ACID signature is valid.");
+    Ok(())
+}
+
 fn create_romfs(input_directory: &Path, output_file: &Path) -> Result<(), linkle::error::Error> {
     let romfs = linkle::format::romfs::RomFs::from_directory(&input_directory)?;
     let mut option = OpenOptions::new();
@@ -264,23 +713,77 @@ fn process_args(app: &Opt) {
             ref icon,
             ref romfs,
             ref nacp,
-        } => create_nxo(
-            "nro",
+            ref embedded_files,
+            ref version,
+            ref flags,
+        } => create_nro(
+            input_file,
+            output_file,
+            to_opt_ref(icon),
+            to_opt_ref(romfs),
+            to_opt_ref(nacp),
+            embedded_files,
+            &NroOptions {
+                version: *version,
+                flags: *flags,
+            },
+        ),
+        Opt::NroAssets {
+            ref input_file,
+            ref output_file,
+            ref icon,
+            ref romfs,
+            ref nacp,
+            ref remove,
+            ref embedded_files,
+            ref remove_embedded,
+        } => replace_nro_assets(
             input_file,
             output_file,
             to_opt_ref(icon),
             to_opt_ref(romfs),
             to_opt_ref(nacp),
+            remove,
+            embedded_files,
+            remove_embedded,
         ),
         Opt::Nso {
             ref input_file,
             ref output_file,
-        } => create_nxo("nso", input_file, output_file, None, None, None),
+            ref module_name,
+            compression,
+            text_compression,
+            rodata_compression,
+            data_compression,
+            ref no_hash_check,
+        } => {
+            let segment_options =
+                |name: &str, segment_compression: Option<NsoCompression>| NsoSegmentOptions {
+                    compression: segment_compression.unwrap_or(*compression),
+                    check_hash: !no_hash_check.iter().any(|v| v == name),
+                };
+            create_nso(
+                input_file,
+                output_file,
+                &NsoOptions {
+                    module_name: module_name.clone(),
+                    text: segment_options("text", *text_compression),
+                    rodata: segment_options("rodata", *rodata_compression),
+                    data: segment_options("data", *data_compression),
+                },
+            )
+        }
+        Opt::NsoExtract {
+            ref input_file,
+            ref output,
+            raw,
+        } => extract_nso(input_file, output, *raw),
         Opt::Kip {
             ref input_file,
             ref npdm_file,
             ref output_file,
         } => create_kip(input_file, npdm_file, output_file),
+        Opt::KipInfo { ref input_file } => print_kip_info(input_file),
         Opt::Pfs0 {
             ref input_directory,
             ref output_file,
@@ -289,10 +792,33 @@ fn process_args(app: &Opt) {
             ref input_file,
             ref output_directory,
         } => extract_pfs0(input_file, output_directory),
+        Opt::NroExtract {
+            ref input_file,
+            ref output_directory,
+            nacp_json,
+            romfs_dir,
+        } => extract_nro(input_file, output_directory, *nacp_json, *romfs_dir),
         Opt::Nacp {
             ref input_file,
             ref output_file,
         } => create_nacp(input_file, output_file),
+        Opt::NacpExtract {
+            ref input_file,
+            ref output_file,
+        } => extract_nacp(input_file, to_opt_ref(output_file)),
+        Opt::Npdm {
+            ref input_file,
+            ref output_file,
+            ref acid_key,
+            fake_sign,
+        } => create_npdm(input_file, output_file, to_opt_ref(acid_key), *fake_sign),
+        Opt::NpdmVerify {
+            ref input_file,
+            ref acid_key,
+            dev,
+            ref keyfile,
+        } => verify_npdm(input_file, to_opt_ref(acid_key), *dev, to_opt_ref(keyfile)),
+        Opt::NpdmInfo { ref input_file } => print_npdm_info(input_file),
         Opt::Romfs {
             ref input_directory,
             ref output_file,
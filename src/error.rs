@@ -48,6 +48,64 @@ pub enum Error {
         error: &'static str,
         backtrace: Backtrace,
     },
+    #[snafu(display("Invalid NRO: {}.", error))]
+    InvalidNro {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid NSO: {}.", error))]
+    InvalidNso {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid KIP: {}.", error))]
+    InvalidKip {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid NACP: {}.", error))]
+    InvalidNacp { error: String, backtrace: Backtrace },
+    #[snafu(display("Invalid NPDM: {}.", error))]
+    InvalidNpdm { error: String, backtrace: Backtrace },
+    #[snafu(display("Invalid kernel capabilities: {}.", error))]
+    InvalidKernelCapabilities { error: String, backtrace: Backtrace },
+    #[snafu(display("Invalid kernel capability {:#010x}: {}.", value, error))]
+    InvalidKernelCapability {
+        value: u32,
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display(
+        "The NRO {} changed while building: expected {} bytes, got {}.",
+        name,
+        expected,
+        written
+    ))]
+    NroAssetChanged {
+        name: String,
+        expected: u64,
+        written: u64,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid RomFS: {}.", error))]
+    InvalidRomFs {
+        error: &'static str,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid icon: {}.", error))]
+    InvalidIcon { error: String, backtrace: Backtrace },
+    #[snafu(display("Failed to convert the icon: {}", error))]
+    Image {
+        error: image::ImageError,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("RSA error: {}", error))]
+    Rsa {
+        error: rsa::errors::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("Invalid RSA key: {}.", error))]
+    InvalidRsaKey { error: String, backtrace: Backtrace },
     #[snafu(display("Failed to convert filename to UTF8: {}.", filename))]
     Utf8Conversion {
         filename: String,
@@ -144,6 +202,24 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+impl From<image::ImageError> for Error {
+    fn from(error: image::ImageError) -> Error {
+        Error::Image {
+            error,
+            backtrace: Backtrace::generate(),
+        }
+    }
+}
+
+impl From<rsa::errors::Error> for Error {
+    fn from(error: rsa::errors::Error) -> Error {
+        Error::Rsa {
+            error,
+            backtrace: Backtrace::generate(),
+        }
+    }
+}
+
 impl From<(usize, cmac::crypto_mac::MacError)> for Error {
     fn from((id, error): (usize, cmac::crypto_mac::MacError)) -> Error {
         Error::MacError {
@@ -0,0 +1,118 @@
+use crate::error::Error;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageFormat};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+
+/// Width and height of the icons displayed by hbmenu and qlaunch.
+pub const ICON_SIZE: u32 = 256;
+
+fn invalid_icon<T: Into<String>>(error: T) -> Error {
+    Error::InvalidIcon {
+        error: error.into(),
+        backtrace: Backtrace::generate(),
+    }
+}
+
+/// Checks that `data` is a 256x256 baseline JPEG, which is the only kind of
+/// icon hbmenu and qlaunch are able to display.
+pub fn validate_icon(data: &[u8]) -> Result<(), Error> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid_icon("not a JPEG"));
+    }
+
+    // Walk the JPEG segments until we find the Start Of Frame.
+    let mut pos = 2;
+    loop {
+        // Markers may be preceded by any number of 0xFF fill bytes.
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let (marker, len) = match data.get(pos..pos + 4) {
+            Some(&[0xFF, marker, len_hi, len_lo]) => {
+                (marker, usize::from(u16::from_be_bytes([len_hi, len_lo])))
+            }
+            _ => return Err(invalid_icon("truncated JPEG")),
+        };
+
+        match marker {
+            // Baseline DCT
+            0xC0 => {
+                let frame = data
+                    .get(pos + 4..pos + 9)
+                    .ok_or_else(|| invalid_icon("truncated JPEG"))?;
+                let height = u32::from(u16::from_be_bytes([frame[1], frame[2]]));
+                let width = u32::from(u16::from_be_bytes([frame[3], frame[4]]));
+                if width != ICON_SIZE || height != ICON_SIZE {
+                    return Err(invalid_icon(format!(
+                        "icon is {}x{}, expected {}x{}",
+                        width, height, ICON_SIZE, ICON_SIZE
+                    )));
+                }
+                return Ok(());
+            }
+            // Every other SOF marker (DHT, JPG and DAC excluded)
+            0xC1..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err(invalid_icon("JPEG is not baseline"));
+            }
+            // Start Of Scan without a frame header
+            0xDA => return Err(invalid_icon("JPEG has no frame header")),
+            _ => pos += 2 + len,
+        }
+    }
+}
+
+/// Converts `data` into a 256x256 baseline JPEG. PNG, BMP and JPEG images of
+/// any size are supported. Icons that are already valid are returned as is.
+pub fn convert_icon(data: &[u8]) -> Result<Vec<u8>, Error> {
+    if validate_icon(data).is_ok() {
+        return Ok(data.to_vec());
+    }
+
+    let format = image::guess_format(data)?;
+    match format {
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Bmp => (),
+        _ => {
+            return Err(invalid_icon(format!(
+                "unsupported image format {:?}, expected JPEG, PNG or BMP",
+                format
+            )))
+        }
+    }
+
+    let mut icon = image::load_from_memory_with_format(data, format)?;
+    let (width, height) = icon.dimensions();
+    if width != height {
+        println!(
+            "Warning: icon is not square ({}x{}), it will be stretched.",
+            width, height
+        );
+    }
+    if width != ICON_SIZE || height != ICON_SIZE {
+        icon = icon.resize_exact(ICON_SIZE, ICON_SIZE, FilterType::Lanczos3);
+    }
+
+    let mut converted = Vec::new();
+    JpegEncoder::new_with_quality(&mut converted, 95).encode_image(&icon.to_rgb8())?;
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    #[test]
+    fn png_is_converted_to_256x256_jpeg() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(64, 64)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        assert!(validate_icon(&png).is_err());
+
+        let jpeg = convert_icon(&png).unwrap();
+        validate_icon(&jpeg).unwrap();
+        assert_eq!(convert_icon(&jpeg).unwrap(), jpeg);
+    }
+}
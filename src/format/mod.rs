@@ -1,5 +1,6 @@
+pub mod icon;
 pub mod nacp;
-mod npdm;
+pub mod npdm;
 pub mod nxo;
 pub mod pfs0;
 pub mod romfs;
@@ -1,16 +1,19 @@
+use crate::error::Error;
 use crate::format::utils;
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::format::utils::{HexData, HexOrNum};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Cursor, Read, Write};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct NacpLangEntry {
     pub name: String,
     pub author: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct NacpLangEntries {
     #[serde(rename = "en-US")]
     pub en_us: Option<NacpLangEntry>,
@@ -41,6 +44,253 @@ pub struct NacpLangEntries {
 
     #[serde(rename = "zh-CN")]
     pub zh_cn: Option<NacpLangEntry>,
+
+    #[serde(rename = "pt-BR")]
+    pub pt_br: Option<NacpLangEntry>,
+}
+
+/// Language codes, in the order of the NACP title entries and of the bits of
+/// the supported language flag.
+const LANGUAGES: [&str; 16] = [
+    "en-US", "en-GB", "ja", "fr", "de", "es-419", "es", "it", "nl", "fr-CA", "pt", "ru", "ko",
+    "zh-TW", "zh-CN", "pt-BR",
+];
+
+/// A single-byte NACP enum.
+pub trait NacpEnum: Sized + Copy + Default {
+    fn from_u8(value: u8) -> Option<Self>;
+    fn to_u8(self) -> u8;
+}
+
+/// The value of a NACP enum field. Values unknown to linkle are kept as their
+/// raw byte, so that they survive a round-trip.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum NacpValue<T> {
+    Named(T),
+    Raw(u8),
+}
+
+impl<T: NacpEnum> NacpValue<T> {
+    fn from_u8(value: u8) -> Self {
+        T::from_u8(value).map_or(NacpValue::Raw(value), NacpValue::Named)
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            NacpValue::Named(value) => value.to_u8(),
+            NacpValue::Raw(value) => value,
+        }
+    }
+}
+
+impl<T: NacpEnum> Default for NacpValue<T> {
+    fn default() -> Self {
+        NacpValue::Named(T::default())
+    }
+}
+
+/// Declares a single-byte NACP enum, serialized by snake_case name. The
+/// variant with value 0 is the default.
+macro_rules! nacp_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:literal,)* }) => {
+        $(#[$meta])*
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+        #[serde(rename_all = "snake_case")]
+        pub enum $name {
+            $($variant = $value,)*
+        }
+
+        impl NacpEnum for $name {
+            fn from_u8(value: u8) -> Option<Self> {
+                match value {
+                    $($value => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+
+            fn to_u8(self) -> u8 {
+                self as u8
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name::from_u8(0).unwrap()
+            }
+        }
+    };
+}
+
+nacp_enum!(
+    /// Whether a user account must be selected before launching the title.
+    StartupUserAccount {
+        None = 0,
+        Required = 1,
+        RequiredWithNetworkServiceAccountAvailable = 2,
+    }
+);
+
+nacp_enum!(AddOnContentRegistrationType {
+    AllOnLaunch = 0,
+    OnDemand = 1,
+});
+
+nacp_enum!(Screenshot {
+    Allow = 0,
+    Deny = 1,
+});
+
+nacp_enum!(VideoCapture {
+    Disable = 0,
+    Manual = 1,
+    Enable = 2,
+});
+
+nacp_enum!(DataLossConfirmation {
+    None = 0,
+    Required = 1,
+});
+
+nacp_enum!(PlayLogPolicy {
+    Open = 0,
+    LogOnly = 1,
+    None = 2,
+    Closed = 3,
+});
+
+nacp_enum!(LogoType {
+    LicensedByNintendo = 0,
+    DistributedByNintendo = 1,
+    Nintendo = 2,
+});
+
+nacp_enum!(LogoHandling {
+    Auto = 0,
+    Manual = 1,
+});
+
+nacp_enum!(RuntimeAddOnContentInstall {
+    Deny = 0,
+    AllowAppend = 1,
+    AllowAppendButDontDownloadWhenUsingNetwork = 2,
+});
+
+nacp_enum!(RuntimeParameterDelivery {
+    Always = 0,
+    AlwaysIfUserStateMatched = 1,
+    OnRestart = 2,
+});
+
+nacp_enum!(CrashReport {
+    Deny = 0,
+    Allow = 1,
+});
+
+nacp_enum!(Hdcp {
+    None = 0,
+    Required = 1,
+});
+
+nacp_enum!(PlayLogQueryCapability {
+    None = 0,
+    WhiteList = 1,
+    All = 2,
+});
+
+/// Minimum age per rating organization. Organizations left out don't rate
+/// the title.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct NacpRatingAge {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cero: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grac_gcrb: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gsrmr: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub esrb: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class_ind: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usk: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pegi: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pegi_portugal: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pegi_bbfc: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub russian: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acb: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oflc: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iarc_generic: Option<u8>,
+}
+
+impl NacpRatingAge {
+    fn from_bytes(data: &[u8]) -> Self {
+        let age = |idx: usize| {
+            if data[idx] == 0xFF {
+                None
+            } else {
+                Some(data[idx])
+            }
+        };
+        NacpRatingAge {
+            cero: age(0),
+            grac_gcrb: age(1),
+            gsrmr: age(2),
+            esrb: age(3),
+            class_ind: age(4),
+            usk: age(5),
+            pegi: age(6),
+            pegi_portugal: age(7),
+            pegi_bbfc: age(8),
+            russian: age(9),
+            acb: age(10),
+            oflc: age(11),
+            iarc_generic: age(12),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; 0x20] {
+        let ages = [
+            self.cero,
+            self.grac_gcrb,
+            self.gsrmr,
+            self.esrb,
+            self.class_ind,
+            self.usk,
+            self.pegi,
+            self.pegi_portugal,
+            self.pegi_bbfc,
+            self.russian,
+            self.acb,
+            self.oflc,
+            self.iarc_generic,
+        ];
+        let mut data = [0xFF; 0x20];
+        for (byte, age) in data.iter_mut().zip(ages.iter()) {
+            if let Some(age) = age {
+                *byte = *age;
+            }
+        }
+        data
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct NacpJitConfiguration {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub memory_size: HexOrNum,
+    /// Flags other than `enabled`, kept as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub other_flags: Option<HexOrNum>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -51,6 +301,166 @@ pub struct NacpFile {
     pub title_id: Option<String>,
     pub dlc_base_title_id: Option<String>,
     pub lang: Option<NacpLangEntries>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isbn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_user_account: Option<NacpValue<StartupUserAccount>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_user_account_optional: Option<bool>,
+    /// Startup user account option flags other than
+    /// `startup_user_account_optional`, kept as is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_startup_user_account_option_flags: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_switch_lock: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_on_content_registration_type: Option<NacpValue<AddOnContentRegistrationType>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub demo: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retail_interactive_display: Option<bool>,
+    /// Attribute flags other than `demo` and `retail_interactive_display`,
+    /// kept as is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_attribute_flags: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub supported_languages: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_communication: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub screenshot: Option<NacpValue<Screenshot>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_capture: Option<NacpValue<VideoCapture>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_loss_confirmation: Option<NacpValue<DataLossConfirmation>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_log_policy: Option<NacpValue<PlayLogPolicy>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_group_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rating_age: Option<NacpRatingAge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub save_data_owner_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_journal_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_account_save_data_journal_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_journal_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_save_data_journal_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temporary_storage_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_journal_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_data_and_journal_size_max: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_storage_index_max: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bcat_delivery_cache_storage_size: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bcat_passphrase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub application_error_code_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_communication_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_type: Option<NacpValue<LogoType>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_handling: Option<NacpValue<LogoHandling>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_add_on_content_install: Option<NacpValue<RuntimeAddOnContentInstall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_parameter_delivery: Option<NacpValue<RuntimeParameterDelivery>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash_report: Option<NacpValue<CrashReport>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdcp: Option<NacpValue<Hdcp>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appropriate_age_for_china: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed_for_pseudo_device_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_log_queryable_application_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub play_log_query_capability: Option<NacpValue<PlayLogQueryCapability>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppress_game_card_access: Option<bool>,
+    /// Repair flags other than `suppress_game_card_access`, kept as is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_repair_flags: Option<HexOrNum>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_index: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required_network_service_license_on_launch: Option<bool>,
+    /// Required network service license on launch flags other than
+    /// `required_network_service_license_on_launch`, kept as is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_required_network_service_license_on_launch_flags: Option<HexOrNum>,
+    /// The raw 0x198-byte neighbor detection client configuration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub neighbor_detection_client_configuration: Option<HexData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jit_configuration: Option<NacpJitConfiguration>,
+    /// The raw 0xC40 bytes following the JIT configuration, which newer SDKs
+    /// use for fields that aren't modeled here.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_data: Option<HexData>,
+}
+
+fn parse_id(id: &str) -> io::Result<u64> {
+    u64::from_str_radix(id, 16).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid title id provided: {}", id),
+        )
+    })
+}
+
+fn parse_ids(ids: &[String], count: usize, name: &str) -> io::Result<Vec<u64>> {
+    if ids.len() > count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("At most {} {} can be provided", count, name),
+        ));
+    }
+    let mut res = ids
+        .iter()
+        .map(|id| parse_id(id))
+        .collect::<io::Result<Vec<u64>>>()?;
+    res.resize(count, 0);
+    Ok(res)
+}
+
+fn write_string<T: Write>(
+    output_writter: &mut T,
+    string: &Option<String>,
+    name: &str,
+    size: usize,
+) -> io::Result<()> {
+    let mut string = string.clone().unwrap_or_default();
+    utils::check_string_or_truncate(&mut string, name, size);
+    output_writter.write_all(string.as_bytes())?;
+    output_writter.write_all(&vec![0; size - string.len()])
+}
+
+fn non_default<T: Default + PartialEq>(value: T) -> Option<T> {
+    if value == T::default() {
+        None
+    } else {
+        Some(value)
+    }
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -63,6 +473,246 @@ impl NacpFile {
         }
     }
 
+    /// Parse a binary NACP back into its JSON representation.
+    pub fn from_reader<R: Read>(mut f: R) -> Result<Self, Error> {
+        let mut data = vec![0; 0x4000];
+        f.read_exact(&mut data)?;
+
+        let mut titles = Vec::with_capacity(16);
+        for entry in data[..0x3000].chunks(0x300) {
+            let name = utils::read_fixed_string(&entry[..0x200])?;
+            let author = utils::read_fixed_string(&entry[0x200..])?;
+            titles.push(NacpLangEntry { name, author });
+        }
+
+        let mut cursor = Cursor::new(&data[0x3000..]);
+        let mut isbn = [0; 0x25];
+        cursor.read_exact(&mut isbn)?;
+        let isbn = utils::read_fixed_string(&isbn)?;
+        let startup_user_account = NacpValue::<StartupUserAccount>::from_u8(cursor.read_u8()?);
+        let user_account_switch_lock = cursor.read_u8()? != 0;
+        let add_on_content_registration_type =
+            NacpValue::<AddOnContentRegistrationType>::from_u8(cursor.read_u8()?);
+        let attribute_flag = cursor.read_u32::<LittleEndian>()?;
+        let supported_language_flag = cursor.read_u32::<LittleEndian>()?;
+        let parental_control_flag = cursor.read_u32::<LittleEndian>()?;
+        let screenshot = NacpValue::<Screenshot>::from_u8(cursor.read_u8()?);
+        let video_capture = NacpValue::<VideoCapture>::from_u8(cursor.read_u8()?);
+        let data_loss_confirmation = NacpValue::<DataLossConfirmation>::from_u8(cursor.read_u8()?);
+        let play_log_policy = NacpValue::<PlayLogPolicy>::from_u8(cursor.read_u8()?);
+        let presence_group_id = cursor.read_u64::<LittleEndian>()?;
+        let mut rating_age = [0; 0x20];
+        cursor.read_exact(&mut rating_age)?;
+        let mut version = [0; 0x10];
+        cursor.read_exact(&mut version)?;
+        let version = utils::read_fixed_string(&version)?;
+        let dlc_base_title_id = cursor.read_u64::<LittleEndian>()?;
+        // The title id is recovered from the save data owner id.
+        let title_id = cursor.read_u64::<LittleEndian>()?;
+        let user_account_save_data_size = cursor.read_u64::<LittleEndian>()?;
+        let user_account_save_data_journal_size = cursor.read_u64::<LittleEndian>()?;
+        let device_save_data_size = cursor.read_u64::<LittleEndian>()?;
+        let device_save_data_journal_size = cursor.read_u64::<LittleEndian>()?;
+        let bcat_delivery_cache_storage_size = cursor.read_u64::<LittleEndian>()?;
+        let mut application_error_code_category = [0; 8];
+        cursor.read_exact(&mut application_error_code_category)?;
+        let application_error_code_category =
+            utils::read_fixed_string(&application_error_code_category)?;
+        let mut local_communication_ids = [0; 8];
+        cursor.read_u64_into::<LittleEndian>(&mut local_communication_ids)?;
+        let logo_type = NacpValue::<LogoType>::from_u8(cursor.read_u8()?);
+        let logo_handling = NacpValue::<LogoHandling>::from_u8(cursor.read_u8()?);
+        let runtime_add_on_content_install =
+            NacpValue::<RuntimeAddOnContentInstall>::from_u8(cursor.read_u8()?);
+        let runtime_parameter_delivery =
+            NacpValue::<RuntimeParameterDelivery>::from_u8(cursor.read_u8()?);
+        let appropriate_age_for_china = cursor.read_u8()?;
+        cursor.read_u8()?;
+        let crash_report = NacpValue::<CrashReport>::from_u8(cursor.read_u8()?);
+        let hdcp = NacpValue::<Hdcp>::from_u8(cursor.read_u8()?);
+        let seed_for_pseudo_device_id = cursor.read_u64::<LittleEndian>()?;
+        let mut bcat_passphrase = [0; 0x41];
+        cursor.read_exact(&mut bcat_passphrase)?;
+        let bcat_passphrase = utils::read_fixed_string(&bcat_passphrase)?;
+        let startup_user_account_option = cursor.read_u8()?;
+        cursor.read_exact(&mut [0; 6])?;
+        let mut sizes = [0; 8];
+        cursor.read_u64_into::<LittleEndian>(&mut sizes)?;
+        let cache_storage_index_max = cursor.read_u16::<LittleEndian>()?;
+        cursor.read_exact(&mut [0; 6])?;
+        let mut play_log_queryable_application_ids = [0; 0x10];
+        cursor.read_u64_into::<LittleEndian>(&mut play_log_queryable_application_ids)?;
+        let play_log_query_capability =
+            NacpValue::<PlayLogQueryCapability>::from_u8(cursor.read_u8()?);
+        let repair_flag = cursor.read_u8()?;
+        let program_index = cursor.read_u8()?;
+        let required_network_service_license_on_launch = cursor.read_u8()?;
+        cursor.read_u32::<LittleEndian>()?;
+        let mut neighbor_detection_client_configuration = vec![0; 0x198];
+        cursor.read_exact(&mut neighbor_detection_client_configuration)?;
+        let jit_flags = cursor.read_u64::<LittleEndian>()?;
+        let jit_configuration = NacpJitConfiguration {
+            enabled: jit_flags & 1 != 0,
+            memory_size: HexOrNum(cursor.read_u64::<LittleEndian>()?),
+            other_flags: non_default(HexOrNum(jit_flags & !1)),
+        };
+        let mut remaining_data = vec![0; 0xC40];
+        cursor.read_exact(&mut remaining_data)?;
+
+        // Only keep the ids that differ from what the writer derives from the
+        // title id.
+        let title_id_default = |id: u64| {
+            if id == title_id {
+                None
+            } else {
+                Some(format!("{:016x}", id))
+            }
+        };
+        let id_list = |ids: &[u64]| {
+            let len = ids.iter().rposition(|&id| id != 0).map_or(0, |pos| pos + 1);
+            ids[..len]
+                .iter()
+                .map(|id| format!("{:016x}", id))
+                .collect::<Vec<_>>()
+        };
+        let size = |size: u64| non_default(HexOrNum(size));
+        let other_flags = |flags: u8| non_default(HexOrNum(u64::from(flags & !1)));
+
+        let supported_languages = LANGUAGES
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| supported_language_flag & (1 << idx) != 0)
+            .map(|(_, lang)| lang.to_string())
+            .collect();
+
+        // The first filled entry is used as the fallback, only keep the
+        // languages that differ from it. Empty entries are kept as such, so
+        // that they aren't replaced by the fallback.
+        let default_lang_entry = titles
+            .iter()
+            .find(|entry| !entry.name.is_empty() || !entry.author.is_empty())
+            .cloned()
+            .unwrap_or(NacpLangEntry {
+                name: String::new(),
+                author: String::new(),
+            });
+        let mut titles = titles.into_iter().map(|entry| {
+            if entry == default_lang_entry {
+                None
+            } else {
+                Some(entry)
+            }
+        });
+        let lang = NacpLangEntries {
+            en_us: titles.next().flatten(),
+            en_gb: titles.next().flatten(),
+            ja: titles.next().flatten(),
+            fr: titles.next().flatten(),
+            de: titles.next().flatten(),
+            es_419: titles.next().flatten(),
+            es: titles.next().flatten(),
+            it: titles.next().flatten(),
+            nl: titles.next().flatten(),
+            fr_ca: titles.next().flatten(),
+            pt: titles.next().flatten(),
+            ru: titles.next().flatten(),
+            ko: titles.next().flatten(),
+            zh_tw: titles.next().flatten(),
+            zh_cn: titles.next().flatten(),
+            pt_br: titles.next().flatten(),
+        };
+        let has_lang = lang != NacpLangEntries::default();
+
+        Ok(NacpFile {
+            name: Some(default_lang_entry.name),
+            author: Some(default_lang_entry.author),
+            version: Some(version),
+            title_id: Some(format!("{:016x}", title_id)),
+            dlc_base_title_id: if dlc_base_title_id == title_id.wrapping_add(0x1000) {
+                None
+            } else {
+                Some(format!("{:016x}", dlc_base_title_id))
+            },
+            lang: if has_lang { Some(lang) } else { None },
+            isbn: non_default(isbn),
+            startup_user_account: non_default(startup_user_account),
+            startup_user_account_optional: non_default(startup_user_account_option & 1 != 0),
+            other_startup_user_account_option_flags: other_flags(startup_user_account_option),
+            user_account_switch_lock: non_default(user_account_switch_lock),
+            add_on_content_registration_type: non_default(add_on_content_registration_type),
+            demo: non_default(attribute_flag & 1 != 0),
+            retail_interactive_display: non_default(attribute_flag & 2 != 0),
+            other_attribute_flags: non_default(HexOrNum(u64::from(attribute_flag & !3))),
+            supported_languages: non_default(supported_languages),
+            free_communication: non_default(parental_control_flag & 1 != 0),
+            screenshot: non_default(screenshot),
+            video_capture: non_default(video_capture),
+            data_loss_confirmation: non_default(data_loss_confirmation),
+            play_log_policy: non_default(play_log_policy),
+            presence_group_id: title_id_default(presence_group_id),
+            rating_age: non_default(NacpRatingAge::from_bytes(&rating_age)),
+            save_data_owner_id: None,
+            user_account_save_data_size: size(user_account_save_data_size),
+            user_account_save_data_journal_size: size(user_account_save_data_journal_size),
+            user_account_save_data_size_max: size(sizes[0]),
+            user_account_save_data_journal_size_max: size(sizes[1]),
+            device_save_data_size: size(device_save_data_size),
+            device_save_data_journal_size: size(device_save_data_journal_size),
+            device_save_data_size_max: size(sizes[2]),
+            device_save_data_journal_size_max: size(sizes[3]),
+            temporary_storage_size: size(sizes[4]),
+            cache_storage_size: size(sizes[5]),
+            cache_storage_journal_size: size(sizes[6]),
+            cache_storage_data_and_journal_size_max: size(sizes[7]),
+            cache_storage_index_max: non_default(cache_storage_index_max),
+            bcat_delivery_cache_storage_size: size(bcat_delivery_cache_storage_size),
+            bcat_passphrase: non_default(bcat_passphrase),
+            application_error_code_category: non_default(application_error_code_category),
+            local_communication_ids: if local_communication_ids[..2] == [title_id, title_id]
+                && local_communication_ids[2..].iter().all(|&id| id == 0)
+            {
+                None
+            } else {
+                Some(id_list(&local_communication_ids))
+            },
+            logo_type: non_default(logo_type),
+            logo_handling: non_default(logo_handling),
+            runtime_add_on_content_install: non_default(runtime_add_on_content_install),
+            runtime_parameter_delivery: non_default(runtime_parameter_delivery),
+            crash_report: non_default(crash_report),
+            hdcp: non_default(hdcp),
+            appropriate_age_for_china: non_default(appropriate_age_for_china),
+            seed_for_pseudo_device_id: title_id_default(seed_for_pseudo_device_id),
+            play_log_queryable_application_ids: non_default(id_list(
+                &play_log_queryable_application_ids,
+            )),
+            play_log_query_capability: non_default(play_log_query_capability),
+            suppress_game_card_access: non_default(repair_flag & 1 != 0),
+            other_repair_flags: other_flags(repair_flag),
+            program_index: non_default(program_index),
+            required_network_service_license_on_launch: non_default(
+                required_network_service_license_on_launch & 1 != 0,
+            ),
+            other_required_network_service_license_on_launch_flags: other_flags(
+                required_network_service_license_on_launch,
+            ),
+            neighbor_detection_client_configuration: if neighbor_detection_client_configuration
+                .iter()
+                .all(|&v| v == 0)
+            {
+                None
+            } else {
+                Some(HexData(neighbor_detection_client_configuration))
+            },
+            jit_configuration: non_default(jit_configuration),
+            remaining_data: if remaining_data.iter().all(|&v| v == 0) {
+                None
+            } else {
+                Some(HexData(remaining_data))
+            },
+        })
+    }
+
     fn write_lang_entry<T>(
         &self,
         output_writter: &mut T,
@@ -104,16 +754,12 @@ impl NacpFile {
 
         let title_id = match &self.title_id {
             None => 0,
-            Some(title_string) => {
-                u64::from_str_radix(title_string.as_str(), 16).expect("Invalid title_id provided!")
-            }
+            Some(title_string) => parse_id(title_string)?,
         };
 
         let dlc_base_title_id = match &self.dlc_base_title_id {
             None => title_id + 0x1000,
-            Some(title_string) => {
-                u64::from_str_radix(title_string.as_str(), 16).expect("Invalid title_id provided!")
-            }
+            Some(title_string) => parse_id(title_string)?,
         };
 
         let lang_entries = &self.lang;
@@ -240,20 +886,65 @@ impl NacpFile {
                         .clone()
                         .unwrap_or_else(|| default_lang_entry.clone()),
                 )?;
-                // There are 16 entries. One is missing :eyes:
-                self.write_lang_entry(output_writter, &default_lang_entry)?;
+                self.write_lang_entry(
+                    output_writter,
+                    &lang_entries
+                        .pt_br
+                        .clone()
+                        .unwrap_or_else(|| default_lang_entry.clone()),
+                )?;
             }
         }
 
-        // 0x3000 - 0x3038: Unknown
-        output_writter.write_all(&[0; 0x38])?;
+        write_string(output_writter, &self.isbn, "isbn", 0x25)?;
+        output_writter.write_u8(self.startup_user_account.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.user_account_switch_lock.unwrap_or_default() as u8)?;
+        output_writter.write_u8(
+            self.add_on_content_registration_type
+                .unwrap_or_default()
+                .to_u8(),
+        )?;
+        let other_attribute_flags = self.other_attribute_flags.unwrap_or_default().0;
+        let other_attribute_flags = u32::try_from(other_attribute_flags).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "other_attribute_flags {:#x} doesn't fit in 32 bits",
+                    other_attribute_flags
+                ),
+            )
+        })?;
+        output_writter.write_u32::<LittleEndian>(
+            self.demo.unwrap_or_default() as u32
+                | (self.retail_interactive_display.unwrap_or_default() as u32) << 1
+                | other_attribute_flags,
+        )?;
+
+        let mut supported_language_flag = 0;
+        for lang in self.supported_languages.iter().flatten() {
+            let idx = LANGUAGES.iter().position(|l| l == lang).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown supported language: {}", lang),
+                )
+            })?;
+            supported_language_flag |= 1 << idx;
+        }
+        output_writter.write_u32::<LittleEndian>(supported_language_flag)?;
+        output_writter
+            .write_u32::<LittleEndian>(self.free_communication.unwrap_or_default() as u32)?;
 
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+        output_writter.write_u8(self.screenshot.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.video_capture.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.data_loss_confirmation.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.play_log_policy.unwrap_or_default().to_u8())?;
 
-        // Unknown 0x20 bytes
-        let mut unknown = Vec::new();
-        unknown.resize(0x20, 0xFF);
-        output_writter.write_all(&unknown)?;
+        let title_id_or = |id: &Option<String>| match id {
+            None => Ok(title_id),
+            Some(id) => parse_id(id),
+        };
+        output_writter.write_u64::<LittleEndian>(title_id_or(&self.presence_group_id)?)?;
+        output_writter.write_all(&self.rating_age.clone().unwrap_or_default().to_bytes())?;
 
         // Version string part (probably UTF8)
         let version_padding = 0x10 - version.len();
@@ -261,24 +952,137 @@ impl NacpFile {
         output_writter.write_all(&vec![0; version_padding])?;
 
         output_writter.write_u64::<LittleEndian>(dlc_base_title_id)?;
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+        output_writter.write_u64::<LittleEndian>(title_id_or(&self.save_data_owner_id)?)?;
 
-        //  0x3080 - 0x30B0: Unknown
-        output_writter.write_all(&[0; 0x30])?;
+        let size = |size: Option<HexOrNum>| size.unwrap_or_default().0;
+        let other_flags = |flags: Option<HexOrNum>, name: &str| {
+            let flags = flags.unwrap_or_default().0;
+            u8::try_from(flags).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} {:#x} doesn't fit in 8 bits", name, flags),
+                )
+            })
+        };
+        output_writter.write_u64::<LittleEndian>(size(self.user_account_save_data_size))?;
+        output_writter.write_u64::<LittleEndian>(size(self.user_account_save_data_journal_size))?;
+        output_writter.write_u64::<LittleEndian>(size(self.device_save_data_size))?;
+        output_writter.write_u64::<LittleEndian>(size(self.device_save_data_journal_size))?;
+        output_writter.write_u64::<LittleEndian>(size(self.bcat_delivery_cache_storage_size))?;
+        write_string(
+            output_writter,
+            &self.application_error_code_category,
+            "application_error_code_category",
+            8,
+        )?;
+
+        // When unspecified, only the first two local communication ids are
+        // set to the title id, other entries seems to be for update titles.
+        let local_communication_ids = match &self.local_communication_ids {
+            None => vec![title_id, title_id, 0, 0, 0, 0, 0, 0],
+            Some(ids) => parse_ids(ids, 8, "local_communication_ids")?,
+        };
+        for id in local_communication_ids {
+            output_writter.write_u64::<LittleEndian>(id)?;
+        }
 
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+        output_writter.write_u8(self.logo_type.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.logo_handling.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(
+            self.runtime_add_on_content_install
+                .unwrap_or_default()
+                .to_u8(),
+        )?;
+        output_writter.write_u8(self.runtime_parameter_delivery.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.appropriate_age_for_china.unwrap_or_default())?;
+        output_writter.write_u8(0)?;
+        output_writter.write_u8(self.crash_report.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(self.hdcp.unwrap_or_default().to_u8())?;
+        output_writter.write_u64::<LittleEndian>(title_id_or(&self.seed_for_pseudo_device_id)?)?;
 
-        // title id array (0x7 entries), only write the base, other entries seems to be for update titles
-        output_writter.write_u64::<LittleEndian>(title_id)?;
-        output_writter.write_all(&[0; 0x30])?;
+        write_string(
+            output_writter,
+            &self.bcat_passphrase,
+            "bcat_passphrase",
+            0x41,
+        )?;
+        output_writter.write_u8(
+            self.startup_user_account_optional.unwrap_or_default() as u8
+                | other_flags(
+                    self.other_startup_user_account_option_flags,
+                    "other_startup_user_account_option_flags",
+                )?,
+        )?;
+        output_writter.write_all(&[0; 6])?;
 
-        // 0x30F0 - 0x30F8: Unknown
-        output_writter.write_u64::<LittleEndian>(0)?;
-        output_writter.write_u64::<LittleEndian>(title_id)?;
+        output_writter.write_u64::<LittleEndian>(size(self.user_account_save_data_size_max))?;
+        output_writter
+            .write_u64::<LittleEndian>(size(self.user_account_save_data_journal_size_max))?;
+        output_writter.write_u64::<LittleEndian>(size(self.device_save_data_size_max))?;
+        output_writter.write_u64::<LittleEndian>(size(self.device_save_data_journal_size_max))?;
+        output_writter.write_u64::<LittleEndian>(size(self.temporary_storage_size))?;
+        output_writter.write_u64::<LittleEndian>(size(self.cache_storage_size))?;
+        output_writter.write_u64::<LittleEndian>(size(self.cache_storage_journal_size))?;
+        output_writter
+            .write_u64::<LittleEndian>(size(self.cache_storage_data_and_journal_size_max))?;
+        output_writter
+            .write_u16::<LittleEndian>(self.cache_storage_index_max.unwrap_or_default())?;
+        output_writter.write_all(&[0; 6])?;
 
-        let mut end_of_file = Vec::new();
-        end_of_file.resize(0xF00, 0);
-        output_writter.write_all(&end_of_file)?;
+        let play_log_queryable_application_ids = parse_ids(
+            self.play_log_queryable_application_ids
+                .as_deref()
+                .unwrap_or_default(),
+            0x10,
+            "play_log_queryable_application_ids",
+        )?;
+        for id in play_log_queryable_application_ids {
+            output_writter.write_u64::<LittleEndian>(id)?;
+        }
+        output_writter.write_u8(self.play_log_query_capability.unwrap_or_default().to_u8())?;
+        output_writter.write_u8(
+            self.suppress_game_card_access.unwrap_or_default() as u8
+                | other_flags(self.other_repair_flags, "other_repair_flags")?,
+        )?;
+        output_writter.write_u8(self.program_index.unwrap_or_default())?;
+        output_writter.write_u8(
+            self.required_network_service_license_on_launch
+                .unwrap_or_default() as u8
+                | other_flags(
+                    self.other_required_network_service_license_on_launch_flags,
+                    "other_required_network_service_license_on_launch_flags",
+                )?,
+        )?;
+
+        // Reserved word, then the neighbor detection configuration.
+        output_writter.write_u32::<LittleEndian>(0)?;
+        match &self.neighbor_detection_client_configuration {
+            Some(data) if data.0.len() != 0x198 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "neighbor_detection_client_configuration must be 0x198 bytes",
+                ))
+            }
+            Some(data) => output_writter.write_all(&data.0)?,
+            None => output_writter.write_all(&[0; 0x198])?,
+        }
+
+        let jit_configuration = self.jit_configuration.unwrap_or_default();
+        output_writter.write_u64::<LittleEndian>(
+            jit_configuration.enabled as u64 | jit_configuration.other_flags.unwrap_or_default().0,
+        )?;
+        output_writter.write_u64::<LittleEndian>(jit_configuration.memory_size.0)?;
+
+        match &self.remaining_data {
+            Some(data) if data.0.len() != 0xC40 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "remaining_data must be 0xC40 bytes",
+                ))
+            }
+            Some(data) => output_writter.write_all(&data.0)?,
+            None => output_writter.write_all(&[0; 0xC40])?,
+        }
 
         Ok(())
     }
@@ -294,4 +1098,185 @@ mod test {
         NacpFile::default().write(&mut buf).unwrap();
         assert_eq!(buf.len(), 0x4000, "Nacp length is wrong");
     }
+
+    #[test]
+    fn nacp_reader_reverses_writer() {
+        let mut nacp: NacpFile = serde_json::from_str(
+            r#"{
+                "name": "Link",
+                "author": "Linkle",
+                "version": "1.0.1",
+                "title_id": "0100000000001000",
+                "dlc_base_title_id": "0100000000003000",
+                "lang": {
+                    "ja": { "name": "リンク", "author": "リンクル" },
+                    "pt-BR": { "name": "Ligação", "author": "Linkle" }
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        nacp.write(&mut buf).unwrap();
+        assert_eq!(&buf[0x2D00..0x2D00 + 9], "Ligação".as_bytes());
+
+        let mut parsed = NacpFile::from_reader(&buf[..]).unwrap();
+        assert_eq!(parsed.name.as_deref(), Some("Link"));
+        assert_eq!(parsed.title_id.as_deref(), Some("0100000000001000"));
+        assert_eq!(
+            parsed.dlc_base_title_id.as_deref(),
+            Some("0100000000003000")
+        );
+        let lang = parsed.lang.as_ref().unwrap();
+        assert_eq!(lang.ja.as_ref().unwrap().name, "リンク");
+        assert_eq!(lang.pt_br.as_ref().unwrap().name, "Ligação");
+        assert_eq!(lang.en_us, None);
+
+        let mut buf2 = Vec::new();
+        parsed.write(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+    }
+
+    #[test]
+    fn application_control_property_layout() {
+        let mut nacp: NacpFile = serde_json::from_str(
+            r#"{
+                "title_id": "0100000000001000",
+                "startup_user_account": "required",
+                "supported_languages": ["en-US", "ja"],
+                "video_capture": "enable",
+                "rating_age": { "pegi": 12 },
+                "user_account_save_data_size": "0x400000",
+                "logo_type": "nintendo",
+                "cache_storage_index_max": 2,
+                "program_index": 1,
+                "jit_configuration": { "enabled": true, "memory_size": "0x100000" }
+            }"#,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        nacp.write(&mut buf).unwrap();
+
+        assert_eq!(buf[0x3025], 1);
+        assert_eq!(buf[0x302C..0x3030], [0b101, 0, 0, 0]);
+        assert_eq!(buf[0x3035], 2);
+        assert_eq!(buf[0x3040 + 6], 12);
+        assert_eq!(buf[0x3040], 0xFF);
+        assert_eq!(buf[0x3080..0x3088], 0x400000u64.to_le_bytes());
+        assert_eq!(buf[0x30F0], 2);
+        assert_eq!(buf[0x30F8..0x3100], 0x0100000000001000u64.to_le_bytes());
+        assert_eq!(buf[0x3188..0x318A], [2, 0]);
+        assert_eq!(buf[0x3212], 1);
+        assert_eq!(buf[0x33B0], 1);
+        assert_eq!(buf[0x33B8..0x33C0], 0x100000u64.to_le_bytes());
+
+        let mut parsed = NacpFile::from_reader(&buf[..]).unwrap();
+        assert_eq!(
+            parsed.video_capture,
+            Some(NacpValue::Named(VideoCapture::Enable))
+        );
+        assert_eq!(parsed.presence_group_id, None);
+        let mut buf2 = Vec::new();
+        parsed.write(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+    }
+
+    #[test]
+    fn nacp_keeps_unknown_values() {
+        let mut nacp: NacpFile =
+            serde_json::from_str(r#"{ "title_id": "0100000000001000" }"#).unwrap();
+        let mut buf = Vec::new();
+        nacp.write(&mut buf).unwrap();
+        buf[0x3028] = 0b1001;
+        buf[0x3035] = 7;
+        buf[0x30F4] = 18;
+        buf[0x3141] = 0b11;
+        buf[0x3211] = 0b10;
+        buf[0x3213] = 0b101;
+        buf[0x3218] = 1;
+        buf[0x33AF] = 2;
+        buf[0x33B0] = 0b10;
+        buf[0x33C0] = 3;
+        buf[0x3FFF] = 4;
+
+        let parsed = NacpFile::from_reader(&buf[..]).unwrap();
+        assert_eq!(parsed.demo, Some(true));
+        assert_eq!(parsed.other_attribute_flags.unwrap().0, 0b1000);
+        assert_eq!(parsed.video_capture, Some(NacpValue::Raw(7)));
+        assert_eq!(parsed.appropriate_age_for_china, Some(18));
+        assert_eq!(parsed.startup_user_account_optional, Some(true));
+        assert_eq!(
+            parsed.other_startup_user_account_option_flags.unwrap().0,
+            0b10
+        );
+        assert_eq!(parsed.suppress_game_card_access, None);
+        assert_eq!(parsed.other_repair_flags.unwrap().0, 0b10);
+        assert_eq!(
+            parsed.required_network_service_license_on_launch,
+            Some(true)
+        );
+        assert_eq!(
+            parsed
+                .other_required_network_service_license_on_launch_flags
+                .unwrap()
+                .0,
+            0b100
+        );
+        let neighbor_detection = parsed.neighbor_detection_client_configuration.unwrap();
+        assert_eq!(neighbor_detection.0[0], 1);
+        assert_eq!(neighbor_detection.0[0x197], 2);
+        let jit_configuration = parsed.jit_configuration.unwrap();
+        assert!(!jit_configuration.enabled);
+        assert_eq!(jit_configuration.other_flags.unwrap().0, 0b10);
+        let remaining_data = parsed.remaining_data.unwrap();
+        assert_eq!(remaining_data.0[0], 3);
+        assert_eq!(remaining_data.0[0xC3F], 4);
+
+        // The JSON representation keeps them as well.
+        let json = serde_json::to_string(&NacpFile::from_reader(&buf[..]).unwrap()).unwrap();
+        let mut parsed: NacpFile = serde_json::from_str(&json).unwrap();
+        let mut buf2 = Vec::new();
+        parsed.write(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+    }
+
+    #[test]
+    fn nacp_keeps_empty_lang_entries() {
+        let mut nacp: NacpFile = serde_json::from_str(
+            r#"{
+                "name": "Link",
+                "author": "Linkle",
+                "lang": { "ja": { "name": "リンク", "author": "リンクル" } }
+            }"#,
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        nacp.write(&mut buf).unwrap();
+        // Leave en-GB and ko out of the language table.
+        for entry in [1, 12].iter() {
+            for byte in buf[entry * 0x300..(entry + 1) * 0x300].iter_mut() {
+                *byte = 0;
+            }
+        }
+
+        let mut parsed = NacpFile::from_reader(&buf[..]).unwrap();
+        assert_eq!(parsed.name.as_deref(), Some("Link"));
+        let lang = parsed.lang.as_ref().unwrap();
+        assert_eq!(lang.en_us, None);
+        assert_eq!(lang.en_gb.as_ref().unwrap().name, "");
+        assert_eq!(lang.ko.as_ref().unwrap().author, "");
+        assert_eq!(lang.ja.as_ref().unwrap().name, "リンク");
+        let mut buf2 = Vec::new();
+        parsed.write(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+
+        // A table without any entry stays empty too.
+        for byte in buf[..0x3000].iter_mut() {
+            *byte = 0;
+        }
+        let mut parsed = NacpFile::from_reader(&buf[..]).unwrap();
+        assert_eq!(parsed.name.as_deref(), Some(""));
+        let mut buf2 = Vec::new();
+        parsed.write(&mut buf2).unwrap();
+        assert_eq!(buf, buf2);
+    }
 }
@@ -1,18 +1,31 @@
+use crate::error::Error;
+use crate::format::pfs0::ReadSeek;
 use crate::format::utils::HexOrNum;
-use crate::format::{nacp::NacpFile, npdm::KernelCapability, romfs::RomFs, utils};
+use crate::format::{icon, nacp::NacpFile, npdm::KernelCapability, romfs::RomFs, utils};
+use crate::utils::{align_up, ReadRange, TryClone};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use elf::types::{Machine, ProgramHeader, SectionHeader, EM_AARCH64, EM_ARM, PT_LOAD, SHT_NOTE};
+use elf::types::{
+    Machine, ProgramHeader, SectionHeader, EM_AARCH64, EM_ARM, ET_DYN, PF_R, PF_W, PF_X,
+    PT_DYNAMIC, PT_LOAD, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_DYNAMIC, SHT_DYNSYM, SHT_NOBITS,
+    SHT_NOTE, SHT_PROGBITS, SHT_STRTAB,
+};
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use snafu::Backtrace;
+use snafu::GenerateBacktrace;
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::fs::File;
-use std::io::{self, Cursor, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
 
-// TODO: Support switchbrew's embedded files for NRO
 pub struct NxoFile {
     file: File,
+    name: String,
     machine: Machine,
     text_segment: ProgramHeader,
     rodata_segment: ProgramHeader,
@@ -22,9 +35,242 @@ pub struct NxoFile {
     dynamic_section: Option<SectionHeader>,
     dynstr_section: Option<SectionHeader>,
     dynsym_section: Option<SectionHeader>,
+    api_info_section: Option<SectionHeader>,
+    dso_handle: Option<u64>,
     build_id: Option<Vec<u8>>,
 }
 
+/// Build options for the fields of the NRO header that can't be derived from
+/// the ELF.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NroOptions {
+    pub version: u32,
+    pub flags: u32,
+}
+
+/// How a segment of an NSO is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsoCompression {
+    None,
+    Lz4,
+    /// LZ4 with the high compression mode. Smaller, but slower to build.
+    Lz4Hc,
+}
+
+impl FromStr for NsoCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(NsoCompression::None),
+            "lz4" => Ok(NsoCompression::Lz4),
+            "lz4hc" => Ok(NsoCompression::Lz4Hc),
+            _ => Err(format!("unknown compression {}", s)),
+        }
+    }
+}
+
+/// Build options for one of the segments of an NSO.
+#[derive(Debug, Clone, Copy)]
+pub struct NsoSegmentOptions {
+    pub compression: NsoCompression,
+    /// Whether the loader should check the hash of the segment.
+    pub check_hash: bool,
+}
+
+impl Default for NsoSegmentOptions {
+    fn default() -> Self {
+        NsoSegmentOptions {
+            compression: NsoCompression::Lz4,
+            check_hash: true,
+        }
+    }
+}
+
+impl NsoSegmentOptions {
+    fn compress(&self, data: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        match self.compression {
+            NsoCompression::None => Ok(data.clone()),
+            NsoCompression::Lz4 => utils::compress_lz4(data),
+            NsoCompression::Lz4Hc => utils::compress_lz4_hc(data),
+        }
+    }
+}
+
+/// Build options for NSOs.
+#[derive(Debug, Clone, Default)]
+pub struct NsoOptions {
+    /// The module path stored at the start of .rodata. Addresses in .rodata
+    /// can't move, so the ELF must reserve the path there: a zero word, the
+    /// reserved length, then that many bytes. Defaults to the ELF file name,
+    /// without its extension.
+    pub module_name: Option<String>,
+    pub text: NsoSegmentOptions,
+    pub rodata: NsoSegmentOptions,
+    pub data: NsoSegmentOptions,
+}
+
+impl NsoOptions {
+    fn flags(&self) -> u32 {
+        let mut flags = 0;
+        let segments = [&self.text, &self.rodata, &self.data];
+        for (index, segment) in segments.iter().enumerate() {
+            if segment.compression != NsoCompression::None {
+                flags |= 1 << index;
+            }
+            if segment.check_hash {
+                flags |= 1 << (index + 3);
+            }
+        }
+        flags
+    }
+}
+
+/// The category of a KIP process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessCategory {
+    RegularTitle = 0,
+    KernelBuiltin = 1,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProcessCategoryRepr {
+    Named(ProcessCategory),
+    Raw(u32),
+}
+
+impl ProcessCategory {
+    pub fn from_u32(value: u32) -> Option<ProcessCategory> {
+        match value {
+            0 => Some(ProcessCategory::RegularTitle),
+            1 => Some(ProcessCategory::KernelBuiltin),
+            _ => None,
+        }
+    }
+}
+
+/// Accepts either the name of the process category or its raw value.
+fn deserialize_process_category<'de, D>(deserializer: D) -> Result<ProcessCategory, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match <ProcessCategoryRepr as serde::Deserialize>::deserialize(deserializer)? {
+        ProcessCategoryRepr::Named(category) => Ok(category),
+        ProcessCategoryRepr::Raw(value) => ProcessCategory::from_u32(value)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid process category {}", value))),
+    }
+}
+
+/// The flags of a KIP.
+///
+/// The compression flags select which segments get BLZ compressed when
+/// writing the KIP, and all default to true. In JSON, the raw flags byte is
+/// accepted too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "KipFlagsRepr")]
+pub struct KipFlags {
+    pub compress_text: bool,
+    pub compress_rodata: bool,
+    pub compress_data: bool,
+    pub is_64_bit: bool,
+    pub is_64_bit_address_space: bool,
+    pub use_system_pool_partition: bool,
+    pub is_immortal: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KipFlagsRepr {
+    Raw(u8),
+    Flags {
+        #[serde(default = "default_true")]
+        compress_text: bool,
+        #[serde(default = "default_true")]
+        compress_rodata: bool,
+        #[serde(default = "default_true")]
+        compress_data: bool,
+        is_64_bit: bool,
+        is_64_bit_address_space: bool,
+        use_system_pool_partition: bool,
+        #[serde(default)]
+        is_immortal: bool,
+    },
+}
+
+impl From<KipFlagsRepr> for KipFlags {
+    fn from(repr: KipFlagsRepr) -> KipFlags {
+        match repr {
+            KipFlagsRepr::Raw(bits) => KipFlags::from_bits(bits),
+            KipFlagsRepr::Flags {
+                compress_text,
+                compress_rodata,
+                compress_data,
+                is_64_bit,
+                is_64_bit_address_space,
+                use_system_pool_partition,
+                is_immortal,
+            } => KipFlags {
+                compress_text,
+                compress_rodata,
+                compress_data,
+                is_64_bit,
+                is_64_bit_address_space,
+                use_system_pool_partition,
+                is_immortal,
+            },
+        }
+    }
+}
+
+impl KipFlags {
+    pub fn from_bits(bits: u8) -> KipFlags {
+        KipFlags {
+            compress_text: bits & (1 << 0) != 0,
+            compress_rodata: bits & (1 << 1) != 0,
+            compress_data: bits & (1 << 2) != 0,
+            is_64_bit: bits & (1 << 3) != 0,
+            is_64_bit_address_space: bits & (1 << 4) != 0,
+            use_system_pool_partition: bits & (1 << 5) != 0,
+            is_immortal: bits & (1 << 6) != 0,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        let flags = [
+            self.compress_text,
+            self.compress_rodata,
+            self.compress_data,
+            self.is_64_bit,
+            self.is_64_bit_address_space,
+            self.use_system_pool_partition,
+            self.is_immortal,
+        ];
+        flags
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (idx, &flag)| bits | (flag as u8) << idx)
+    }
+
+    /// The default flags for an ELF of the given machine type.
+    fn for_machine(machine: Machine) -> KipFlags {
+        KipFlags {
+            compress_text: true,
+            compress_rodata: true,
+            compress_data: true,
+            is_64_bit: machine == EM_AARCH64,
+            is_64_bit_address_space: machine == EM_AARCH64,
+            use_system_pool_partition: true,
+            is_immortal: false,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KipNpdm {
     name: String,
@@ -32,11 +278,57 @@ pub struct KipNpdm {
     main_thread_stack_size: HexOrNum,
     main_thread_priority: u8,
     default_cpu_id: u8,
-    process_category: u8,
-    flags: Option<u8>,
+    #[serde(deserialize_with = "deserialize_process_category")]
+    process_category: ProcessCategory,
+    flags: Option<KipFlags>,
     kernel_capabilities: Vec<KernelCapability>,
 }
 
+impl KipNpdm {
+    /// Rebuilds the NPDM a KIP was built from, so that it can be rebuilt with
+    /// `NxoFile::write_kip1`.
+    pub fn from_kip(header: &Kip1Header) -> Result<KipNpdm, Error> {
+        let flags = KipFlags::from_bits(header.flags);
+        if flags.bits() != header.flags {
+            return Err(Error::InvalidKip {
+                error: "unknown flags",
+                backtrace: Backtrace::generate(),
+            });
+        }
+        Ok(KipNpdm {
+            name: header.name.clone(),
+            title_id: HexOrNum(header.title_id),
+            main_thread_stack_size: HexOrNum(u64::from(header.main_thread_stack_size())),
+            main_thread_priority: header.main_thread_priority,
+            default_cpu_id: header.default_cpu_id,
+            process_category: ProcessCategory::from_u32(header.process_category).ok_or_else(
+                || Error::InvalidKip {
+                    error: "unknown process category",
+                    backtrace: Backtrace::generate(),
+                },
+            )?,
+            flags: Some(flags),
+            kernel_capabilities: KernelCapability::decode(&header.kernel_capabilities)?,
+        })
+    }
+}
+
+/// BLZ compress a KIP segment if `compress` is set. The segment is kept as-is,
+/// and `compress` cleared, when BLZ can't shrink it.
+fn compress_kip_segment(mut segment_data: Vec<u8>, compress: &mut bool) -> Result<Vec<u8>, Error> {
+    if *compress {
+        let compressed = utils::compress_blz(&mut segment_data).map_err(|_| Error::InvalidKip {
+            error: "segment couldn't be BLZ compressed",
+            backtrace: Backtrace::generate(),
+        })?;
+        if compressed.len() < segment_data.len() {
+            return Ok(compressed);
+        }
+        *compress = false;
+    }
+    Ok(segment_data)
+}
+
 fn pad_segment(previous_segment_data: &mut Vec<u8>, offset: usize, segment: &ProgramHeader) {
     let segment_vaddr = segment.vaddr as usize;
     let segment_supposed_start = previous_segment_data.len() + offset;
@@ -82,6 +374,54 @@ where
     Ok(())
 }
 
+/// Write the location of `section` relative to the start of .rodata, as NSO
+/// and NRO headers expect it.
+fn write_rodata_relative<T>(
+    output_writter: &mut T,
+    rodata_segment: &ProgramHeader,
+    section: &Option<SectionHeader>,
+) -> std::io::Result<()>
+where
+    T: Write,
+{
+    let in_rodata = |section: &SectionHeader| {
+        let rodata_end = rodata_segment.vaddr.checked_add(rodata_segment.memsz);
+        match (section.addr.checked_add(section.size), rodata_end) {
+            (Some(end), Some(rodata_end)) => {
+                section.addr >= rodata_segment.vaddr && end <= rodata_end
+            }
+            _ => false,
+        }
+    };
+    let (offset, size) = match section {
+        Some(section) if in_rodata(section) => {
+            match (
+                u32::try_from(section.addr - rodata_segment.vaddr),
+                u32::try_from(section.size),
+            ) {
+                (Ok(offset), Ok(size)) => (offset, size),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} doesn't fit in a 32-bit header", section.name),
+                    ))
+                }
+            }
+        }
+        Some(section) => {
+            println!(
+                "Warning: {} is not in .rodata, it won't be referenced by the header",
+                section.name
+            );
+            (0, 0)
+        }
+        None => (0, 0),
+    };
+    output_writter.write_u32::<LittleEndian>(offset)?;
+    output_writter.write_u32::<LittleEndian>(size)?;
+    Ok(())
+}
+
 fn write_mod0<T>(
     nxo_file: &NxoFile,
     offset: u32,
@@ -128,6 +468,10 @@ where
 impl NxoFile {
     pub fn from_elf(input: &str) -> std::io::Result<Self> {
         let path = PathBuf::from(input);
+        let name = path
+            .file_stem()
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_default();
         let mut file = File::open(path)?;
 
         let elf_file = elf::File::open_stream(&mut file).unwrap();
@@ -170,6 +514,8 @@ impl NxoFile {
         let mut dynstr_section = None;
         let mut dynsym_section = None;
         let mut eh_frame_hdr_section = None;
+        let mut api_info_section = None;
+        let mut dso_handle = None;
 
         for section in sections {
             if section.shdr.shtype == SHT_NOTE {
@@ -188,12 +534,22 @@ impl NxoFile {
                 ".dynstr" => dynstr_section = Some(section.shdr.clone()),
                 ".dynsym" => dynsym_section = Some(section.shdr.clone()),
                 ".eh_frame_hdr" => eh_frame_hdr_section = Some(section.shdr.clone()),
+                ".api_info" => api_info_section = Some(section.shdr.clone()),
                 _ => (),
             }
+            if dso_handle.is_none() {
+                if let Ok(symbols) = elf_file.get_symbols(section) {
+                    dso_handle = symbols
+                        .iter()
+                        .find(|v| v.name == "__dso_handle")
+                        .map(|v| v.value);
+                }
+            }
         }
 
         Ok(NxoFile {
             file,
+            name,
             machine: elf_file.ehdr.machine,
             text_segment: *text_segment,
             rodata_segment: *rodata_segment,
@@ -204,16 +560,17 @@ impl NxoFile {
             dynstr_section,
             dynsym_section,
             eh_frame_hdr_section,
+            api_info_section,
+            dso_handle,
         })
     }
 
     pub fn write_nro<T>(
         &mut self,
         output_writter: &mut T,
-        romfs: Option<RomFs>,
-        icon: Option<&str>,
-        nacp: Option<NacpFile>,
-    ) -> std::io::Result<()>
+        assets: &mut NroAssets,
+        options: &NroOptions,
+    ) -> Result<(), Error>
     where
         T: Write,
     {
@@ -247,11 +604,11 @@ impl NxoFile {
         // NRO magic
         output_writter.write_all(b"NRO0")?;
         // Version
-        output_writter.write_u32::<LittleEndian>(0)?;
+        output_writter.write_u32::<LittleEndian>(options.version)?;
         // Total size
         output_writter.write_u32::<LittleEndian>(total_len)?;
         // Flags
-        output_writter.write_u32::<LittleEndian>(0)?;
+        output_writter.write_u32::<LittleEndian>(options.flags)?;
 
         // Segment Header (3 entries)
         let mut file_offset = 0;
@@ -312,41 +669,27 @@ impl NxoFile {
 
         write_build_id(&self.build_id, output_writter, &code, &rodata, &data)?;
 
-        // TODO: DSO Module Offset (unused)
-        output_writter.write_u32::<LittleEndian>(0)?;
+        // DSO handle offset
+        let dso_handle = match self.dso_handle {
+            Some(dso_handle) => u32::try_from(dso_handle).map_err(|_| Error::InvalidNro {
+                error: "__dso_handle is out of the 32-bit address space",
+                backtrace: Backtrace::generate(),
+            })?,
+            None => {
+                println!("Warning: __dso_handle not found (stripped ELF?), the NRO DSO handle offset will be 0");
+                0
+            }
+        };
+        output_writter.write_u32::<LittleEndian>(dso_handle)?;
         // Reserved (unused)
         output_writter.write_u32::<LittleEndian>(0)?;
 
-        // TODO: apiInfo
-        output_writter.write_u64::<LittleEndian>(0)?;
-
-        // .dynstr section info
-        output_writter.write_u32::<LittleEndian>(
-            self.dynstr_section
-                .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
-        )?;
-        output_writter.write_u32::<LittleEndian>(
-            self.dynstr_section
-                .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
-        )?;
-
-        // .dynsym section info
-        output_writter.write_u32::<LittleEndian>(
-            self.dynsym_section
-                .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
-        )?;
-        output_writter.write_u32::<LittleEndian>(
-            self.dynsym_section
-                .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
-        )?;
+        // SegmentHeaderRelative for .api_info
+        write_rodata_relative(output_writter, rodata_segment, &self.api_info_section)?;
+        // SegmentHeaderRelative for .dynstr
+        write_rodata_relative(output_writter, rodata_segment, &self.dynstr_section)?;
+        // SegmentHeaderRelative for .dynsym
+        write_rodata_relative(output_writter, rodata_segment, &self.dynsym_section)?;
 
         let module_offset = u32::from_le_bytes(code[4..8].try_into().unwrap()) as usize;
         if module_offset != 0
@@ -411,71 +754,19 @@ impl NxoFile {
         }
 
         // Early return if there's no need for an ASET segment.
-        if let (None, None, None) = (&icon, &romfs, &nacp) {
+        if assets.is_empty() {
             return Ok(());
         }
 
-        // Aset handling
-        output_writter.write_all(b"ASET")?;
-        output_writter.write_u32::<LittleEndian>(0)?; // version
-
-        // Offset to the next available region.
-        let mut offset = 8 + 16 + 16 + 16;
-
-        let icon_len = if let Some(icon) = &icon {
-            // TODO: Check if icon is a 256x256 JPEG. Convert it if it isn't?
-            let icon_len = Path::new(icon).metadata()?.len();
-            output_writter.write_u64::<LittleEndian>(offset)?;
-            output_writter.write_u64::<LittleEndian>(icon_len)?;
-            icon_len
-        } else {
-            output_writter.write_u64::<LittleEndian>(0)?;
-            output_writter.write_u64::<LittleEndian>(0)?;
-            0
-        };
-
-        offset += icon_len;
-
-        let nacp_len = if let Some(nacp) = &nacp {
-            let nacp_len = nacp.len() as u64;
-            output_writter.write_u64::<LittleEndian>(offset)?;
-            output_writter.write_u64::<LittleEndian>(nacp_len)?;
-            nacp_len
-        } else {
-            output_writter.write_u64::<LittleEndian>(0)?;
-            output_writter.write_u64::<LittleEndian>(0)?;
-            0
-        };
-
-        offset += nacp_len;
-
-        if let Some(romfs) = &romfs {
-            output_writter.write_u64::<LittleEndian>(offset)?;
-            output_writter.write_u64::<LittleEndian>(romfs.len() as u64)?;
-        } else {
-            output_writter.write_u64::<LittleEndian>(0)?;
-            output_writter.write_u64::<LittleEndian>(0)?;
-        };
-
-        if let Some(icon) = icon {
-            assert_eq!(
-                io::copy(&mut File::open(icon)?, output_writter)?,
-                icon_len,
-                "Icon changed while building."
-            );
-        }
-
-        if let Some(mut nacp) = nacp {
-            nacp.write(output_writter)?;
-        }
-
-        if let Some(romfs) = romfs {
-            romfs.write(output_writter)?;
-        }
+        assets.write(output_writter)?;
         Ok(())
     }
 
-    pub fn write_nso<T>(&mut self, output_writter: &mut T) -> std::io::Result<()>
+    pub fn write_nso<T>(
+        &mut self,
+        output_writter: &mut T,
+        options: &NsoOptions,
+    ) -> std::io::Result<()>
     where
         T: Write,
     {
@@ -504,41 +795,72 @@ impl NxoFile {
         // Reserved
         output_writter.write_u32::<LittleEndian>(0)?;
 
-        // Flags, set compression + sum check
-        output_writter.write_u32::<LittleEndian>(0x3F)?;
+        // Flags, compression + sum check of each segment
+        output_writter.write_u32::<LittleEndian>(options.flags())?;
+
+        // The module path starts .rodata: a zero word, the path length, then
+        // the path. Addresses can't move, so it must fit in the space the ELF
+        // reserves for it. An explicit name that doesn't is an error, the
+        // default one only gives a warning.
+        let module_name = options.module_name.as_ref().unwrap_or(&self.name);
+        let module_name_range = match module_path_range(&rodata) {
+            Some(reserved) if module_name.len() <= reserved.len() => {
+                for v in rodata[reserved.clone()].iter_mut() {
+                    *v = 0;
+                }
+                let name_range = reserved.start..reserved.start + module_name.len();
+                rodata[name_range.clone()].copy_from_slice(module_name.as_bytes());
+                rodata[4..8].copy_from_slice(&(module_name.len() as u32).to_le_bytes());
+                Some(name_range)
+            }
+            reserved => {
+                let error = if reserved.is_some() {
+                    "the module name doesn't fit in the module path reserved by the ELF"
+                } else {
+                    "the ELF doesn't reserve a module path at the start of .rodata"
+                };
+                if options.module_name.is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+                }
+                println!("Warning: {}, the module name isn't stored", error);
+                reserved
+            }
+        };
+        let (module_name_offset, module_name_size) =
+            module_name_range.map_or((0, 0), |v| (v.start as u32, v.len() as u32));
 
         // Segment Header (3 entries)
         let mut file_offset = 0x100;
 
         // .text segment
         let code_size = code.len() as u32;
-        let compressed_code = utils::compress_lz4(&mut code)?;
+        let compressed_code = options.text.compress(&mut code)?;
         let compressed_code_size = compressed_code.len() as u32;
         output_writter.write_u32::<LittleEndian>(file_offset as u32)?;
         output_writter.write_u32::<LittleEndian>(text_segment.vaddr as u32)?;
         output_writter.write_u32::<LittleEndian>(code_size as u32)?;
 
-        // TODO: Module Name Offset
-        output_writter.write_u32::<LittleEndian>(0)?;
+        // Module Name Offset, relative to .rodata
+        output_writter.write_u32::<LittleEndian>(module_name_offset)?;
 
         file_offset += compressed_code_size;
 
         // .rodata segment
         let rodata_size = rodata.len() as u32;
-        let compressed_rodata = utils::compress_lz4(&mut rodata)?;
+        let compressed_rodata = options.rodata.compress(&mut rodata)?;
         let compressed_rodata_size = compressed_rodata.len() as u32;
         output_writter.write_u32::<LittleEndian>(file_offset as u32)?;
         output_writter.write_u32::<LittleEndian>(rodata_segment.vaddr as u32)?;
         output_writter.write_u32::<LittleEndian>(rodata_size as u32)?;
 
-        // TODO: Module Name Size
-        output_writter.write_u32::<LittleEndian>(0)?;
+        // Module Name Size
+        output_writter.write_u32::<LittleEndian>(module_name_size)?;
 
         file_offset += compressed_rodata_size;
 
         // .data segment
         let data_size = data.len() as u32;
-        let compressed_data = utils::compress_lz4(&mut data)?;
+        let compressed_data = options.data.compress(&mut data)?;
         let compressed_data_size = compressed_data.len() as u32;
         let uncompressed_data_size = data.len() as u64;
         output_writter.write_u32::<LittleEndian>(file_offset as u32)?;
@@ -578,34 +900,12 @@ impl NxoFile {
         output_writter.write_u64::<LittleEndian>(0)?;
         output_writter.write_u64::<LittleEndian>(0)?;
 
-        // TODO: SegmentHeaderRelative for .api_info
-        output_writter.write_u64::<LittleEndian>(0)?;
+        // SegmentHeaderRelative for .api_info
+        write_rodata_relative(output_writter, rodata_segment, &self.api_info_section)?;
         // SegmentHeaderRelative for .dynstr
-        output_writter.write_u32::<LittleEndian>(
-            self.dynstr_section
-                .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
-        )?;
-        output_writter.write_u32::<LittleEndian>(
-            self.dynstr_section
-                .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
-        )?;
+        write_rodata_relative(output_writter, rodata_segment, &self.dynstr_section)?;
         // SegmentHeaderRelative for .dynsym
-        output_writter.write_u32::<LittleEndian>(
-            self.dynsym_section
-                .as_ref()
-                .map(|v| u32::try_from(v.addr).unwrap())
-                .unwrap_or(0),
-        )?;
-        output_writter.write_u32::<LittleEndian>(
-            self.dynsym_section
-                .as_ref()
-                .map(|v| u32::try_from(v.size).unwrap())
-                .unwrap_or(0),
-        )?;
+        write_rodata_relative(output_writter, rodata_segment, &self.dynsym_section)?;
 
         // .text sha256
         let text_sum = utils::calculate_sha256(&code)?;
@@ -626,55 +926,88 @@ impl NxoFile {
         Ok(())
     }
 
-    pub fn write_kip1<T>(&mut self, output_writer: &mut T, npdm: &KipNpdm) -> std::io::Result<()>
+    pub fn write_kip1<T>(&mut self, output_writer: &mut T, npdm: &KipNpdm) -> Result<(), Error>
     where
         T: Write,
     {
+        KernelCapability::validate_all(&npdm.kernel_capabilities)?;
+        KernelCapability::validate_default_cpu_id(&npdm.kernel_capabilities, npdm.default_cpu_id)?;
+        let caps = npdm
+            .kernel_capabilities
+            .iter()
+            .map(|v| v.encode())
+            .flatten()
+            .collect::<Vec<u32>>();
+        if caps.len() > 0x20 {
+            return Err(Error::InvalidKernelCapabilities {
+                error: format!(
+                    "kernel_capabilities encode to {} descriptors, a KIP holds at most 0x20",
+                    caps.len()
+                ),
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        if self.machine != EM_AARCH64 && self.machine != EM_ARM {
+            return Err(Error::InvalidKip {
+                error: "unknown machine type, expected ARM or AArch64",
+                backtrace: Backtrace::generate(),
+            });
+        }
+        let mut flags = npdm
+            .flags
+            .unwrap_or_else(|| KipFlags::for_machine(self.machine));
+
+        let mut segments = Vec::new();
+        for (segment, compress) in [
+            (&self.text_segment, &mut flags.compress_text),
+            (&self.rodata_segment, &mut flags.compress_rodata),
+            (&self.data_segment, &mut flags.compress_data),
+        ]
+        .iter_mut()
+        {
+            let segment_data = utils::get_segment_data(&mut self.file, segment)?;
+            segments.push(compress_kip_segment(segment_data, *compress)?);
+        }
+        let data_data = segments.pop().unwrap();
+        let rodata_data = segments.pop().unwrap();
+        let text_data = segments.pop().unwrap();
+
         output_writer.write_all(b"KIP1")?;
         let mut name: Vec<u8> = npdm.name.clone().into();
         name.resize(12, 0);
         output_writer.write_all(&name[..])?;
         output_writer.write_u64::<LittleEndian>(npdm.title_id.0)?; // TitleId
-        output_writer.write_u32::<LittleEndian>(u32::from(npdm.process_category))?;
+        output_writer.write_u32::<LittleEndian>(npdm.process_category as u32)?;
         output_writer.write_u8(npdm.main_thread_priority)?;
         output_writer.write_u8(npdm.default_cpu_id)?;
         output_writer.write_u8(0)?; // Reserved
-        if let Some(flags) = npdm.flags {
-            output_writer.write_u8(flags)?;
-        } else if self.machine == EM_AARCH64 {
-            // Compression enable, Is64Bit, IsAddrSpace32Bit, UseSystemPoolPartition
-            output_writer.write_u8(0b0011_1111)?;
-        } else if self.machine == EM_ARM {
-            // Compression enable, UseSystemPoolPartition
-            output_writer.write_u8(0b0010_0111)?;
-        } else {
-            unimplemented!("Unknown machine type");
-        }
+        output_writer.write_u8(flags.bits())?;
 
-        let mut segment_data = utils::get_segment_data(&mut self.file, &self.text_segment)?;
-        let text_data = utils::compress_blz(&mut segment_data).unwrap();
-        let mut segment_data = utils::get_segment_data(&mut self.file, &self.rodata_segment)?;
-        let rodata_data = utils::compress_blz(&mut segment_data).unwrap();
-        let mut segment_data = utils::get_segment_data(&mut self.file, &self.data_segment)?;
-        let data_data = utils::compress_blz(&mut segment_data).unwrap();
+        let main_thread_stack_size =
+            u32::try_from(npdm.main_thread_stack_size.0).map_err(|_| Error::InvalidKip {
+                error: "main_thread_stack_size doesn't fit in 32 bits",
+                backtrace: Backtrace::generate(),
+            })?;
 
         write_kip_segment_header(output_writer, &self.text_segment, 0, text_data.len() as u32)?;
         write_kip_segment_header(
             output_writer,
             &self.rodata_segment,
-            u32::try_from(npdm.main_thread_stack_size.0)
-                .expect("Exected main_thread_stack_size to be an u32"),
+            main_thread_stack_size,
             rodata_data.len() as u32,
         )?;
         write_kip_segment_header(output_writer, &self.data_segment, 0, data_data.len() as u32)?;
 
+        let bss_too_big = |_| Error::InvalidKip {
+            error: "bss doesn't fit in 32 bits",
+            backtrace: Backtrace::generate(),
+        };
         if let Some(segment) = self.bss_segment {
-            output_writer.write_u32::<LittleEndian>(
-                u32::try_from(segment.vaddr).expect("BSS vaddr too big"),
-            )?;
-            output_writer.write_u32::<LittleEndian>(
-                u32::try_from(segment.memsz).expect("BSS memsize too big"),
-            )?;
+            output_writer
+                .write_u32::<LittleEndian>(u32::try_from(segment.vaddr).map_err(bss_too_big)?)?;
+            output_writer
+                .write_u32::<LittleEndian>(u32::try_from(segment.memsz).map_err(bss_too_big)?)?;
         } else {
             // in this case the bss is missing or is embedeed in .data. libnx does that, let's support it
             let data_segment_size = (self.data_segment.filesz + 0xFFF) & !0xFFF;
@@ -684,7 +1017,7 @@ impl NxoFile {
                 0
             };
             output_writer.write_u32::<LittleEndian>(
-                u32::try_from(self.data_segment.vaddr + data_segment_size).unwrap(),
+                u32::try_from(self.data_segment.vaddr + data_segment_size).map_err(bss_too_big)?,
             )?;
             output_writer.write_u32::<LittleEndian>(bss_size)?;
         }
@@ -700,17 +1033,6 @@ impl NxoFile {
         }
 
         // Kernel caps:
-        let caps = npdm
-            .kernel_capabilities
-            .iter()
-            .map(|v| v.encode())
-            .flatten()
-            .collect::<Vec<u32>>();
-        assert!(
-            caps.len() < 0x20,
-            "kernel_capabilities should have less than 0x20 entries!"
-        );
-
         unsafe {
             // Safety: This is safe. I'm just casting a slice of u32 to a slice of u8
             // for fuck's sake.
@@ -749,3 +1071,1836 @@ where
 
     Ok(())
 }
+
+/// The content of one of the slots of an NRO's ASET section.
+pub enum NroAsset {
+    /// Raw data, copied as is.
+    Raw(Box<dyn ReadSeek>),
+    Nacp(Box<NacpFile>),
+    RomFs(RomFs),
+}
+
+impl NroAsset {
+    /// Raw data read from `reader`, which is kept around until the asset is
+    /// written.
+    pub fn from_reader<R: Read + Seek + 'static>(reader: R) -> NroAsset {
+        NroAsset::Raw(Box::new(reader))
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        match self {
+            NroAsset::Raw(file) => file.seek(SeekFrom::End(0)),
+            NroAsset::Nacp(nacp) => Ok(nacp.len() as u64),
+            NroAsset::RomFs(romfs) => Ok(romfs.len() as u64),
+        }
+    }
+
+    fn write<T: Write>(&mut self, output_writter: &mut T) -> io::Result<()> {
+        match self {
+            NroAsset::Raw(file) => {
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(file, output_writter)?;
+                Ok(())
+            }
+            NroAsset::Nacp(nacp) => nacp.write(output_writter),
+            NroAsset::RomFs(romfs) => romfs.write(output_writter),
+        }
+    }
+
+    fn into_reader(mut self) -> io::Result<Box<dyn ReadSeek>> {
+        match self {
+            NroAsset::Raw(file) => Ok(file),
+            _ => {
+                let mut data = Vec::new();
+                self.write(&mut data)?;
+                Ok(Box::new(Cursor::new(data)))
+            }
+        }
+    }
+
+    /// An icon read from `reader`, converted to a 256x256 baseline JPEG if it
+    /// isn't one already.
+    pub fn icon_from_reader<R: Read>(mut reader: R) -> Result<NroAsset, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(NroAsset::from(icon::convert_icon(&data)?))
+    }
+}
+
+impl From<Vec<u8>> for NroAsset {
+    fn from(data: Vec<u8>) -> NroAsset {
+        NroAsset::Raw(Box::new(Cursor::new(data)))
+    }
+}
+
+impl From<NacpFile> for NroAsset {
+    fn from(nacp: NacpFile) -> NroAsset {
+        NroAsset::Nacp(Box::new(nacp))
+    }
+}
+
+impl From<RomFs> for NroAsset {
+    fn from(romfs: RomFs) -> NroAsset {
+        NroAsset::RomFs(romfs)
+    }
+}
+
+/// Counts the bytes going through a writer.
+struct CountingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    count: u64,
+}
+
+impl<'a, W: Write> Write for CountingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Directory of the RomFS holding the named files embedded in an NRO.
+pub const NRO_EMBEDDED_FILES_DIR: &str = "embedded";
+
+/// The only layout of the ASET header: an icon, a NACP and a RomFS.
+const NRO_ASSET_VERSION: u32 = 0;
+
+fn embedded_file_path(name: &str) -> String {
+    format!("{}/{}", NRO_EMBEDDED_FILES_DIR, name)
+}
+
+/// Moves the embedded files out of `romfs`, and returns them by name.
+pub fn take_embedded_files(romfs: &mut RomFs) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let files = romfs.read_dir_files(NRO_EMBEDDED_FILES_DIR)?;
+    for name in files.keys() {
+        romfs.remove_file(&embedded_file_path(name));
+    }
+    romfs.remove_dir(NRO_EMBEDDED_FILES_DIR);
+    Ok(files)
+}
+
+fn check_no_embedded_files_dir(romfs: &RomFs) -> Result<(), Error> {
+    if romfs.contains(NRO_EMBEDDED_FILES_DIR) {
+        return Err(Error::InvalidNro {
+            error:
+                "the RomFS has an embedded entry at its root, which is reserved for embedded files",
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `name` can be used as the name of an embedded file. Names are
+/// used as file names in the RomFS, and on extraction.
+fn validate_embedded_file_name(name: &str) -> Result<(), Error> {
+    let error = if name.is_empty() || name == "." || name == ".." {
+        "embedded file name is invalid"
+    } else if name.contains(|c| c == '/' || c == '\\' || c == '\0') {
+        "embedded file name contains an invalid character"
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidNro {
+        error,
+        backtrace: Backtrace::generate(),
+    })
+}
+
+fn write_nro_asset<T: Write>(
+    output_writter: &mut T,
+    name: &str,
+    asset: &mut NroAsset,
+    size: u64,
+) -> Result<(), Error> {
+    let mut counter = CountingWriter {
+        inner: output_writter,
+        count: 0,
+    };
+    asset.write(&mut counter)?;
+    if counter.count != size {
+        return Err(Error::NroAssetChanged {
+            name: name.to_string(),
+            expected: size,
+            written: counter.count,
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(())
+}
+
+/// The assets stored in the ASET section of an NRO.
+///
+/// Assets are written as is. New icons should be created with
+/// `NroAsset::icon_from_reader`, so they are displayable by the homebrew
+/// menu.
+///
+/// Besides the icon, NACP and RomFS understood by the homebrew menu, an NRO
+/// may carry arbitrary named files. The ASET header has no room for them, so
+/// they are stored in the `embedded` directory of the RomFS, where the
+/// homebrew can read them back at runtime. They are kept in `files` apart
+/// from the RomFS, so replacing or removing the RomFS keeps them. The RomFS
+/// itself must not have an `embedded` root entry.
+#[derive(Default)]
+pub struct NroAssets {
+    pub icon: Option<NroAsset>,
+    pub nacp: Option<NroAsset>,
+    pub romfs: Option<NroAsset>,
+    /// Named files to add to the RomFS when writing, replacing any embedded
+    /// file with the same name.
+    pub files: BTreeMap<String, NroAsset>,
+}
+
+impl NroAssets {
+    pub fn is_empty(&self) -> bool {
+        self.icon.is_none() && self.nacp.is_none() && self.romfs.is_none() && self.files.is_empty()
+    }
+
+    /// The RomFS, parsed if it was given as raw data, or created if missing.
+    fn romfs_mut(&mut self) -> Result<&mut RomFs, Error> {
+        let romfs = match self.romfs.take() {
+            None => RomFs::empty(),
+            Some(NroAsset::RomFs(romfs)) => romfs,
+            Some(mut asset) => {
+                let mut data = Vec::new();
+                asset.write(&mut data)?;
+                RomFs::from_reader(Cursor::new(Rc::<[u8]>::from(data)))?
+            }
+        };
+        self.romfs = Some(NroAsset::RomFs(romfs));
+        match &mut self.romfs {
+            Some(NroAsset::RomFs(romfs)) => Ok(romfs),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Removes the embedded file named `name`. Returns whether it existed.
+    pub fn remove_embedded_file(&mut self, name: &str) -> bool {
+        self.files.remove(name).is_some()
+    }
+
+    fn write<T: Write>(&mut self, output_writter: &mut T) -> Result<(), Error> {
+        if let Some(NroAsset::RomFs(romfs)) = &self.romfs {
+            check_no_embedded_files_dir(romfs)?;
+        }
+        if !self.files.is_empty() {
+            for name in self.files.keys() {
+                validate_embedded_file_name(name)?;
+            }
+            let files = std::mem::take(&mut self.files);
+            let romfs = self.romfs_mut()?;
+            check_no_embedded_files_dir(romfs)?;
+            for (name, file) in files {
+                romfs.push_reader(file.into_reader()?, &embedded_file_path(&name))?;
+            }
+        }
+
+        output_writter.write_all(b"ASET")?;
+        output_writter.write_u32::<LittleEndian>(NRO_ASSET_VERSION)?;
+
+        // Offset to the next available region.
+        let mut offset = 8 + 16 * 3;
+
+        let mut assets = [&mut self.icon, &mut self.nacp, &mut self.romfs];
+        let mut sizes = [0; 3];
+        for (asset, size) in assets.iter_mut().zip(sizes.iter_mut()) {
+            if let Some(asset) = asset {
+                *size = asset.len()?;
+                output_writter.write_u64::<LittleEndian>(offset)?;
+                output_writter.write_u64::<LittleEndian>(*size)?;
+                offset += *size;
+            } else {
+                output_writter.write_u64::<LittleEndian>(0)?;
+                output_writter.write_u64::<LittleEndian>(0)?;
+            }
+        }
+
+        let names = ["icon", "NACP", "RomFS"];
+        for ((asset, size), name) in assets.iter_mut().zip(sizes.iter()).zip(names.iter()) {
+            if let Some(asset) = asset {
+                write_nro_asset(output_writter, name, asset, *size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Location of a segment inside an NRO, relative to the start of the file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NroSegment {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// Location of an asset, relative to the start of the ASET section.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NroAssetSection {
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct NroHeader {
+    pub mod0_offset: u32,
+    pub version: u32,
+    pub size: u32,
+    pub flags: u32,
+    pub text: NroSegment,
+    pub rodata: NroSegment,
+    pub data: NroSegment,
+    pub bss_size: u32,
+    pub build_id: [u8; 0x20],
+    pub dso_handle_offset: u32,
+    pub api_info: NroSegment,
+    pub dynstr: NroSegment,
+    pub dynsym: NroSegment,
+}
+
+#[derive(Debug, Clone)]
+pub struct NroAssetHeader {
+    pub version: u32,
+    pub icon: NroAssetSection,
+    pub nacp: NroAssetSection,
+    pub romfs: NroAssetSection,
+}
+
+/// An existing NRO file. Segments and assets are read lazily from the
+/// underlying stream.
+pub struct NroFile<R> {
+    file: R,
+    header: NroHeader,
+    asset_header: Option<NroAssetHeader>,
+}
+
+fn read_nro_segment<R: Read>(f: &mut R) -> io::Result<NroSegment> {
+    Ok(NroSegment {
+        offset: f.read_u32::<LittleEndian>()?,
+        size: f.read_u32::<LittleEndian>()?,
+    })
+}
+
+fn read_nro_asset_section<R: Read>(f: &mut R) -> io::Result<NroAssetSection> {
+    Ok(NroAssetSection {
+        offset: f.read_u64::<LittleEndian>()?,
+        size: f.read_u64::<LittleEndian>()?,
+    })
+}
+
+fn check_nro_asset_bounds(
+    nro_size: u32,
+    section: NroAssetSection,
+    file_size: u64,
+) -> Result<(), Error> {
+    let end = u64::from(nro_size)
+        .checked_add(section.offset)
+        .and_then(|v| v.checked_add(section.size));
+    match end {
+        Some(end) if end <= file_size => Ok(()),
+        _ => Err(Error::InvalidNro {
+            error: "asset is out of bounds",
+            backtrace: Backtrace::generate(),
+        }),
+    }
+}
+
+impl<R: Read + Seek + TryClone> NroFile<R> {
+    pub fn from_reader(mut f: R) -> Result<Self, Error> {
+        let file_size = f.seek(SeekFrom::End(0))?;
+        if file_size < 0x80 {
+            return Err(Error::InvalidNro {
+                error: "file is too small",
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        // Start header
+        f.seek(SeekFrom::Start(4))?;
+        let mod0_offset = f.read_u32::<LittleEndian>()?;
+        f.seek(SeekFrom::Start(0x10))?;
+
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != b"NRO0" {
+            return Err(Error::InvalidNro {
+                error: "magic is wrong",
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        let version = f.read_u32::<LittleEndian>()?;
+        let size = f.read_u32::<LittleEndian>()?;
+        let flags = f.read_u32::<LittleEndian>()?;
+        let text = read_nro_segment(&mut f)?;
+        let rodata = read_nro_segment(&mut f)?;
+        let data = read_nro_segment(&mut f)?;
+        let bss_size = f.read_u32::<LittleEndian>()?;
+        let _reserved = f.read_u32::<LittleEndian>()?;
+        let mut build_id = [0; 0x20];
+        f.read_exact(&mut build_id)?;
+        let dso_handle_offset = f.read_u32::<LittleEndian>()?;
+        let _reserved = f.read_u32::<LittleEndian>()?;
+        let api_info = read_nro_segment(&mut f)?;
+        let dynstr = read_nro_segment(&mut f)?;
+        let dynsym = read_nro_segment(&mut f)?;
+
+        if u64::from(size) > file_size {
+            return Err(Error::InvalidNro {
+                error: "header size is bigger than the file",
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        for segment in &[text, rodata, data] {
+            if u64::from(segment.offset) + u64::from(segment.size) > u64::from(size) {
+                return Err(Error::InvalidNro {
+                    error: "segment is out of bounds",
+                    backtrace: Backtrace::generate(),
+                });
+            }
+        }
+
+        let header = NroHeader {
+            mod0_offset,
+            version,
+            size,
+            flags,
+            text,
+            rodata,
+            data,
+            bss_size,
+            build_id,
+            dso_handle_offset,
+            api_info,
+            dynstr,
+            dynsym,
+        };
+
+        // The ASET section is optional, and directly follows the NRO image.
+        let asset_header = if file_size >= u64::from(size) + 0x38 {
+            f.seek(SeekFrom::Start(u64::from(size)))?;
+            f.read_exact(&mut magic)?;
+            if &magic == b"ASET" {
+                let version = f.read_u32::<LittleEndian>()?;
+                if version != NRO_ASSET_VERSION {
+                    return Err(Error::InvalidNro {
+                        error: "unsupported ASET version",
+                        backtrace: Backtrace::generate(),
+                    });
+                }
+                let icon = read_nro_asset_section(&mut f)?;
+                let nacp = read_nro_asset_section(&mut f)?;
+                let romfs = read_nro_asset_section(&mut f)?;
+                for section in &[icon, nacp, romfs] {
+                    check_nro_asset_bounds(size, *section, file_size)?;
+                }
+                Some(NroAssetHeader {
+                    version,
+                    icon,
+                    nacp,
+                    romfs,
+                })
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        Ok(NroFile {
+            file: f,
+            header,
+            asset_header,
+        })
+    }
+
+    pub fn header(&self) -> &NroHeader {
+        &self.header
+    }
+
+    pub fn asset_header(&self) -> Option<&NroAssetHeader> {
+        self.asset_header.as_ref()
+    }
+
+    fn range(&self, offset: u64, size: u64) -> io::Result<ReadRange<R>> {
+        let mut range = ReadRange::new(self.file.try_clone()?, offset, size);
+        range.seek(SeekFrom::Start(0))?;
+        Ok(range)
+    }
+
+    fn segment(&self, segment: NroSegment) -> io::Result<ReadRange<R>> {
+        self.range(u64::from(segment.offset), u64::from(segment.size))
+    }
+
+    fn asset(
+        &self,
+        section: impl Fn(&NroAssetHeader) -> NroAssetSection,
+    ) -> io::Result<Option<ReadRange<R>>> {
+        match self.asset_header.as_ref().map(section) {
+            Some(section) if section.size != 0 => Ok(Some(
+                self.range(u64::from(self.header.size) + section.offset, section.size)?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn text(&self) -> io::Result<ReadRange<R>> {
+        self.segment(self.header.text)
+    }
+
+    pub fn rodata(&self) -> io::Result<ReadRange<R>> {
+        self.segment(self.header.rodata)
+    }
+
+    pub fn data(&self) -> io::Result<ReadRange<R>> {
+        self.segment(self.header.data)
+    }
+
+    /// The icon stored in the ASET section, if any.
+    pub fn icon(&self) -> io::Result<Option<ReadRange<R>>> {
+        self.asset(|v| v.icon)
+    }
+
+    /// The raw NACP stored in the ASET section, if any.
+    pub fn nacp(&self) -> io::Result<Option<ReadRange<R>>> {
+        self.asset(|v| v.nacp)
+    }
+
+    /// The raw RomFS image stored in the ASET section, if any.
+    pub fn romfs(&self) -> io::Result<Option<ReadRange<R>>> {
+        self.asset(|v| v.romfs)
+    }
+
+    /// The named files embedded in the RomFS, by name.
+    pub fn embedded_files(&self) -> Result<BTreeMap<String, Vec<u8>>, Error>
+    where
+        R: 'static,
+    {
+        match self.romfs()? {
+            Some(romfs) => Ok(RomFs::from_reader(romfs)?.read_dir_files(NRO_EMBEDDED_FILES_DIR)?),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The current assets of this NRO. The embedded files are moved out of
+    /// the RomFS, the other assets are kept as raw data.
+    pub fn assets(&self) -> Result<NroAssets, Error>
+    where
+        R: 'static,
+    {
+        let mut romfs = self.romfs()?.map(|v| NroAsset::Raw(Box::new(v)));
+        let mut files = BTreeMap::new();
+        if !self.embedded_files()?.is_empty() {
+            let mut parsed = RomFs::from_reader(self.romfs()?.unwrap())?;
+            for (name, data) in take_embedded_files(&mut parsed)? {
+                files.insert(name, NroAsset::from(data));
+            }
+            romfs = Some(NroAsset::RomFs(parsed));
+        }
+        Ok(NroAssets {
+            icon: self.icon()?.map(|v| NroAsset::Raw(Box::new(v))),
+            nacp: self.nacp()?.map(|v| NroAsset::Raw(Box::new(v))),
+            romfs,
+            files,
+        })
+    }
+
+    /// Write this NRO with its ASET section replaced by `assets`. The code
+    /// segments are copied as is.
+    pub fn write_with_assets<T: Write>(
+        &self,
+        output_writter: &mut T,
+        assets: &mut NroAssets,
+    ) -> Result<(), Error> {
+        let mut image = self.range(0, u64::from(self.header.size))?;
+        io::copy(&mut image, output_writter)?;
+
+        if !assets.is_empty() {
+            assets.write(output_writter)?;
+        }
+        Ok(())
+    }
+}
+
+/// Location of an extent inside the .rodata segment of an NSO.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NsoRodataExtent {
+    pub offset: u32,
+    pub size: u32,
+}
+
+/// A segment header of an NSO.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NsoSegment {
+    pub file_offset: u32,
+    pub memory_offset: u32,
+    pub size: u32,
+    pub compressed_size: u32,
+    pub hash: [u8; 0x20],
+}
+
+#[derive(Debug, Clone)]
+pub struct NsoHeader {
+    pub version: u32,
+    pub flags: u32,
+    pub text: NsoSegment,
+    pub rodata: NsoSegment,
+    pub data: NsoSegment,
+    /// Location of the module path in .rodata.
+    pub module_name_offset: u32,
+    pub module_name_size: u32,
+    pub bss_size: u32,
+    pub build_id: [u8; 0x20],
+    pub api_info: NsoRodataExtent,
+    pub dynstr: NsoRodataExtent,
+    pub dynsym: NsoRodataExtent,
+}
+
+impl NsoHeader {
+    /// Whether the segment at `index` (text, rodata, data) is LZ4 compressed.
+    pub fn is_compressed(&self, index: u32) -> bool {
+        self.flags & (1 << index) != 0
+    }
+
+    /// Whether the hash of the segment at `index` (text, rodata, data) should
+    /// be checked when loading it.
+    pub fn check_hash(&self, index: u32) -> bool {
+        self.flags & (1 << (index + 3)) != 0
+    }
+}
+
+/// An existing NSO file. Segments are decompressed, and their hashes checked,
+/// when it is read.
+pub struct NsoFile {
+    header: NsoHeader,
+    module_name: String,
+    text: Vec<u8>,
+    rodata: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn read_nso_rodata_extent<R: Read>(f: &mut R) -> io::Result<NsoRodataExtent> {
+    Ok(NsoRodataExtent {
+        offset: f.read_u32::<LittleEndian>()?,
+        size: f.read_u32::<LittleEndian>()?,
+    })
+}
+
+fn read_nso_segment_data<R: Read + Seek>(
+    f: &mut R,
+    header: &NsoHeader,
+    index: u32,
+    segment: &NsoSegment,
+) -> Result<Vec<u8>, Error> {
+    let file_size = if header.is_compressed(index) {
+        segment.compressed_size
+    } else {
+        segment.size
+    };
+    // Check the sizes before allocating anything. LZ4 can't shrink data more
+    // than 255 times.
+    let input_size = f.seek(SeekFrom::End(0))?;
+    let error = if u64::from(segment.file_offset) + u64::from(file_size) > input_size {
+        Some([
+            ".text is out of bounds",
+            ".rodata is out of bounds",
+            ".data is out of bounds",
+        ])
+    } else if segment.size > i32::MAX as u32
+        || (header.is_compressed(index)
+            && u64::from(segment.size) > u64::from(segment.compressed_size) * 255 + 0x10)
+    {
+        Some([".text is too big", ".rodata is too big", ".data is too big"])
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return Err(Error::InvalidNso {
+            error: error[index as usize],
+            backtrace: Backtrace::generate(),
+        });
+    }
+
+    f.seek(SeekFrom::Start(u64::from(segment.file_offset)))?;
+    let mut data = vec![0; file_size as usize];
+    f.read_exact(&mut data)?;
+
+    if header.is_compressed(index) {
+        data = match lz4::block::decompress(&data, Some(segment.size as i32)) {
+            Ok(data) if data.len() == segment.size as usize => data,
+            _ => {
+                return Err(Error::InvalidNso {
+                    error: [
+                        "failed to decompress .text",
+                        "failed to decompress .rodata",
+                        "failed to decompress .data",
+                    ][index as usize],
+                    backtrace: Backtrace::generate(),
+                })
+            }
+        };
+    }
+
+    if header.check_hash(index) && utils::calculate_sha256(&data)? != segment.hash {
+        return Err(Error::InvalidNso {
+            error: [
+                ".text hash mismatch",
+                ".rodata hash mismatch",
+                ".data hash mismatch",
+            ][index as usize],
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(data)
+}
+
+impl NsoFile {
+    pub fn from_reader<R: Read + Seek>(mut f: R) -> Result<Self, Error> {
+        f.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != b"NSO0" {
+            return Err(Error::InvalidNso {
+                error: "magic is wrong",
+                backtrace: Backtrace::generate(),
+            });
+        }
+        let version = f.read_u32::<LittleEndian>()?;
+        let _reserved = f.read_u32::<LittleEndian>()?;
+        let flags = f.read_u32::<LittleEndian>()?;
+
+        let mut segments = [NsoSegment::default(); 3];
+        let mut extra = [0; 3];
+        for (segment, extra) in segments.iter_mut().zip(extra.iter_mut()) {
+            segment.file_offset = f.read_u32::<LittleEndian>()?;
+            segment.memory_offset = f.read_u32::<LittleEndian>()?;
+            segment.size = f.read_u32::<LittleEndian>()?;
+            *extra = f.read_u32::<LittleEndian>()?;
+        }
+        let [module_name_offset, module_name_size, bss_size] = extra;
+
+        let mut build_id = [0; 0x20];
+        f.read_exact(&mut build_id)?;
+
+        for segment in segments.iter_mut() {
+            segment.compressed_size = f.read_u32::<LittleEndian>()?;
+        }
+
+        // Reserved
+        f.seek(SeekFrom::Current(0x1C))?;
+
+        let api_info = read_nso_rodata_extent(&mut f)?;
+        let dynstr = read_nso_rodata_extent(&mut f)?;
+        let dynsym = read_nso_rodata_extent(&mut f)?;
+
+        for segment in segments.iter_mut() {
+            f.read_exact(&mut segment.hash)?;
+        }
+
+        let [text, rodata, data] = segments;
+        let header = NsoHeader {
+            version,
+            flags,
+            text,
+            rodata,
+            data,
+            module_name_offset,
+            module_name_size,
+            bss_size,
+            build_id,
+            api_info,
+            dynstr,
+            dynsym,
+        };
+
+        let text = read_nso_segment_data(&mut f, &header, 0, &header.text)?;
+        let rodata = read_nso_segment_data(&mut f, &header, 1, &header.rodata)?;
+        let data = read_nso_segment_data(&mut f, &header, 2, &header.data)?;
+
+        let module_name = match module_path_range(&rodata) {
+            Some(range) => utils::read_fixed_string(&rodata[range])?,
+            None => String::new(),
+        };
+
+        Ok(NsoFile {
+            header,
+            module_name,
+            text,
+            rodata,
+            data,
+        })
+    }
+
+    pub fn header(&self) -> &NsoHeader {
+        &self.header
+    }
+
+    /// The module path stored at the start of .rodata, if any.
+    pub fn module_name(&self) -> &str {
+        &self.module_name
+    }
+
+    /// The decompressed .text segment.
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// The decompressed .rodata segment.
+    pub fn rodata(&self) -> &[u8] {
+        &self.rodata
+    }
+
+    /// The decompressed .data segment.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether this NSO holds 32-bit ARM code. NSOs don't record it, so this
+    /// looks at the entrypoint, which is an ARM `b` instruction in 32-bit
+    /// modules.
+    pub fn is_32_bit(&self) -> bool {
+        self.text.get(3) == Some(&0xEA)
+    }
+
+    /// Write an ELF with the segments of this NSO loaded at their memory
+    /// offsets, so that it can be loaded in a disassembler. 32-bit modules
+    /// give an ARM ELF32, others an AArch64 ELF64.
+    ///
+    /// The dynamic section is located through the MOD0 header, and the .dynstr
+    /// and .dynsym sections through the NSO header.
+    pub fn write_elf<T: Write>(&self, output_writter: &mut T) -> Result<(), Error> {
+        let header = &self.header;
+        let class = ElfClass {
+            is_32_bit: self.is_32_bit(),
+        };
+
+        // Rebuild the memory image of the module.
+        let segments = [
+            (&header.text, &self.text),
+            (&header.rodata, &self.rodata),
+            (&header.data, &self.data),
+        ];
+        let image_size = segments
+            .iter()
+            .map(|(segment, data)| u64::from(segment.memory_offset) + data.len() as u64)
+            .max()
+            .unwrap_or(0);
+        if image_size > i32::MAX as u64 {
+            return Err(Error::InvalidNso {
+                error: "module image is too big",
+                backtrace: Backtrace::generate(),
+            });
+        }
+        let mut image = vec![0; image_size as usize];
+        for (segment, data) in segments.iter() {
+            let start = segment.memory_offset as usize;
+            image[start..start + data.len()].copy_from_slice(data);
+        }
+
+        let bss_start = u64::from(header.data.memory_offset) + self.data.len() as u64;
+        let dynamic = find_dynamic(&image, class)?;
+        let rodata_extent = |extent: NsoRodataExtent| {
+            if extent.size != 0 {
+                Some((
+                    u64::from(header.rodata.memory_offset) + u64::from(extent.offset),
+                    u64::from(extent.size),
+                ))
+            } else {
+                None
+            }
+        };
+
+        // The image is placed right after the headers, at a page-aligned
+        // offset, so that file offsets and addresses stay congruent.
+        const IMAGE_OFFSET: u64 = 0x1000;
+
+        let mut phdrs = vec![
+            ElfSegment::load(PF_R.0 | PF_X.0, &header.text, self.text.len()),
+            ElfSegment::load(PF_R.0, &header.rodata, self.rodata.len()),
+            ElfSegment::load(PF_R.0 | PF_W.0, &header.data, self.data.len()),
+        ];
+        if header.bss_size != 0 {
+            phdrs.push(ElfSegment {
+                p_type: PT_LOAD.0,
+                flags: PF_R.0 | PF_W.0,
+                vaddr: bss_start,
+                filesz: 0,
+                memsz: u64::from(header.bss_size),
+                align: 0x1000,
+            });
+        }
+        if let Some((addr, size)) = dynamic {
+            phdrs.push(ElfSegment {
+                p_type: PT_DYNAMIC.0,
+                flags: PF_R.0 | PF_W.0,
+                vaddr: addr,
+                filesz: size,
+                memsz: size,
+                align: class.word_size(),
+            });
+        }
+
+        let mut shdrs = vec![
+            ElfSection::default(),
+            ElfSection::alloc(
+                ".text",
+                SHT_PROGBITS.0,
+                SHF_EXECINSTR.0,
+                &header.text,
+                self.text.len(),
+            ),
+            ElfSection::alloc(
+                ".rodata",
+                SHT_PROGBITS.0,
+                0,
+                &header.rodata,
+                self.rodata.len(),
+            ),
+            ElfSection::alloc(
+                ".data",
+                SHT_PROGBITS.0,
+                SHF_WRITE.0,
+                &header.data,
+                self.data.len(),
+            ),
+        ];
+        if header.bss_size != 0 {
+            shdrs.push(ElfSection {
+                name: ".bss",
+                sh_type: SHT_NOBITS.0,
+                flags: SHF_ALLOC.0 | SHF_WRITE.0,
+                addr: bss_start,
+                size: u64::from(header.bss_size),
+                align: 0x10,
+                ..ElfSection::default()
+            });
+        }
+        let mut dynstr_index = 0;
+        if let Some((addr, size)) = rodata_extent(header.dynstr) {
+            dynstr_index = shdrs.len() as u32;
+            shdrs.push(ElfSection {
+                name: ".dynstr",
+                sh_type: SHT_STRTAB.0,
+                flags: SHF_ALLOC.0,
+                addr,
+                size,
+                align: 1,
+                ..ElfSection::default()
+            });
+        }
+        if let Some((addr, size)) = rodata_extent(header.dynsym) {
+            shdrs.push(ElfSection {
+                name: ".dynsym",
+                sh_type: SHT_DYNSYM.0,
+                flags: SHF_ALLOC.0,
+                addr,
+                size,
+                link: dynstr_index,
+                // Index of the first non-local symbol.
+                info: 1,
+                align: class.word_size(),
+                entsize: class.sym_size(),
+                ..ElfSection::default()
+            });
+        }
+        if let Some((addr, size)) = dynamic {
+            shdrs.push(ElfSection {
+                name: ".dynamic",
+                sh_type: SHT_DYNAMIC.0,
+                flags: SHF_ALLOC.0 | SHF_WRITE.0,
+                addr,
+                size,
+                link: dynstr_index,
+                align: class.word_size(),
+                entsize: class.dyn_size(),
+                ..ElfSection::default()
+            });
+        }
+
+        let mut shstrtab = vec![0];
+        let mut name_offsets = Vec::with_capacity(shdrs.len() + 1);
+        for shdr in shdrs.iter() {
+            if shdr.name.is_empty() {
+                name_offsets.push(0);
+            } else {
+                name_offsets.push(shstrtab.len() as u32);
+                shstrtab.extend_from_slice(shdr.name.as_bytes());
+                shstrtab.push(0);
+            }
+        }
+        name_offsets.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(b".shstrtab\0");
+        let shstrtab_offset = IMAGE_OFFSET + image.len() as u64;
+        shdrs.push(ElfSection {
+            name: ".shstrtab",
+            sh_type: SHT_STRTAB.0,
+            offset: shstrtab_offset,
+            size: shstrtab.len() as u64,
+            align: 1,
+            ..ElfSection::default()
+        });
+        let shoff = align_up(shstrtab_offset + shstrtab.len() as u64, 8);
+
+        // ELF header
+        output_writter.write_all(b"\x7fELF")?;
+        // Class, little endian, version 1, System V ABI
+        let ei_class = if class.is_32_bit { 1 } else { 2 };
+        output_writter.write_all(&[ei_class, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0])?;
+        output_writter.write_u16::<LittleEndian>(ET_DYN.0)?;
+        let machine = if class.is_32_bit { EM_ARM } else { EM_AARCH64 };
+        output_writter.write_u16::<LittleEndian>(machine.0)?;
+        output_writter.write_u32::<LittleEndian>(1)?; // version
+        class.write_word(output_writter, u64::from(header.text.memory_offset))?; // entry
+        class.write_word(output_writter, class.ehdr_size())?; // phoff
+        class.write_word(output_writter, shoff)?;
+        // flags: EABI version 5 for ARM, none for AArch64
+        let flags = if class.is_32_bit { 0x0500_0000 } else { 0 };
+        output_writter.write_u32::<LittleEndian>(flags)?;
+        output_writter.write_u16::<LittleEndian>(class.ehdr_size() as u16)?;
+        output_writter.write_u16::<LittleEndian>(class.phdr_size() as u16)?;
+        output_writter.write_u16::<LittleEndian>(phdrs.len() as u16)?;
+        output_writter.write_u16::<LittleEndian>(class.shdr_size() as u16)?;
+        output_writter.write_u16::<LittleEndian>(shdrs.len() as u16)?;
+        output_writter.write_u16::<LittleEndian>(shdrs.len() as u16 - 1)?; // shstrndx
+
+        for phdr in phdrs.iter() {
+            phdr.write(output_writter, class, IMAGE_OFFSET)?;
+        }
+        let headers_size = class.ehdr_size() + class.phdr_size() * phdrs.len() as u64;
+        output_writter.write_all(&vec![0; (IMAGE_OFFSET - headers_size) as usize])?;
+
+        output_writter.write_all(&image)?;
+        output_writter.write_all(&shstrtab)?;
+        let padding = shoff - (shstrtab_offset + shstrtab.len() as u64);
+        output_writter.write_all(&vec![0; padding as usize])?;
+
+        for (shdr, name) in shdrs.iter_mut().zip(name_offsets.iter()) {
+            if shdr.flags & SHF_ALLOC.0 != 0 {
+                shdr.offset = IMAGE_OFFSET + shdr.addr;
+            }
+            shdr.write(output_writter, class, *name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The class of the ELF built by `NsoFile::write_elf`, which decides the size
+/// of its headers and words.
+#[derive(Clone, Copy)]
+struct ElfClass {
+    is_32_bit: bool,
+}
+
+impl ElfClass {
+    fn word_size(self) -> u64 {
+        if self.is_32_bit {
+            4
+        } else {
+            8
+        }
+    }
+
+    fn ehdr_size(self) -> u64 {
+        if self.is_32_bit {
+            0x34
+        } else {
+            0x40
+        }
+    }
+
+    fn phdr_size(self) -> u64 {
+        if self.is_32_bit {
+            0x20
+        } else {
+            0x38
+        }
+    }
+
+    fn shdr_size(self) -> u64 {
+        if self.is_32_bit {
+            0x28
+        } else {
+            0x40
+        }
+    }
+
+    fn sym_size(self) -> u64 {
+        if self.is_32_bit {
+            0x10
+        } else {
+            0x18
+        }
+    }
+
+    fn dyn_size(self) -> u64 {
+        self.word_size() * 2
+    }
+
+    /// Write an address, offset or size, which are 32-bit wide in ELF32.
+    fn write_word<T: Write>(self, output_writter: &mut T, value: u64) -> io::Result<()> {
+        if self.is_32_bit {
+            let value = u32::try_from(value).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "value doesn't fit in a 32-bit ELF",
+                )
+            })?;
+            output_writter.write_u32::<LittleEndian>(value)
+        } else {
+            output_writter.write_u64::<LittleEndian>(value)
+        }
+    }
+}
+
+/// A program header of the ELF built by `NsoFile::write_elf`.
+struct ElfSegment {
+    p_type: u32,
+    flags: u32,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+impl ElfSegment {
+    fn load(flags: u32, segment: &NsoSegment, size: usize) -> ElfSegment {
+        ElfSegment {
+            p_type: PT_LOAD.0,
+            flags,
+            vaddr: u64::from(segment.memory_offset),
+            filesz: size as u64,
+            memsz: size as u64,
+            align: 0x1000,
+        }
+    }
+
+    fn write<T: Write>(
+        &self,
+        output_writter: &mut T,
+        class: ElfClass,
+        image_offset: u64,
+    ) -> io::Result<()> {
+        // Keep the offset congruent to the address, even for segments
+        // without any data in the file.
+        let offset = image_offset + self.vaddr;
+        output_writter.write_u32::<LittleEndian>(self.p_type)?;
+        if class.is_32_bit {
+            class.write_word(output_writter, offset)?;
+            class.write_word(output_writter, self.vaddr)?;
+            class.write_word(output_writter, self.vaddr)?;
+            class.write_word(output_writter, self.filesz)?;
+            class.write_word(output_writter, self.memsz)?;
+            output_writter.write_u32::<LittleEndian>(self.flags)?;
+            class.write_word(output_writter, self.align)?;
+        } else {
+            output_writter.write_u32::<LittleEndian>(self.flags)?;
+            class.write_word(output_writter, offset)?;
+            class.write_word(output_writter, self.vaddr)?;
+            class.write_word(output_writter, self.vaddr)?;
+            class.write_word(output_writter, self.filesz)?;
+            class.write_word(output_writter, self.memsz)?;
+            class.write_word(output_writter, self.align)?;
+        }
+        Ok(())
+    }
+}
+
+/// A section header of the ELF built by `NsoFile::write_elf`.
+#[derive(Default)]
+struct ElfSection {
+    name: &'static str,
+    sh_type: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl ElfSection {
+    fn alloc(
+        name: &'static str,
+        sh_type: u32,
+        flags: u64,
+        segment: &NsoSegment,
+        size: usize,
+    ) -> ElfSection {
+        ElfSection {
+            name,
+            sh_type,
+            flags: SHF_ALLOC.0 | flags,
+            addr: u64::from(segment.memory_offset),
+            size: size as u64,
+            align: 0x1000,
+            ..ElfSection::default()
+        }
+    }
+
+    fn write<T: Write>(
+        &self,
+        output_writter: &mut T,
+        class: ElfClass,
+        name_offset: u32,
+    ) -> io::Result<()> {
+        output_writter.write_u32::<LittleEndian>(name_offset)?;
+        output_writter.write_u32::<LittleEndian>(self.sh_type)?;
+        class.write_word(output_writter, self.flags)?;
+        class.write_word(output_writter, self.addr)?;
+        class.write_word(output_writter, self.offset)?;
+        class.write_word(output_writter, self.size)?;
+        output_writter.write_u32::<LittleEndian>(self.link)?;
+        output_writter.write_u32::<LittleEndian>(self.info)?;
+        class.write_word(output_writter, self.align)?;
+        class.write_word(output_writter, self.entsize)?;
+        Ok(())
+    }
+}
+
+/// Locate the module path at the start of a .rodata segment: a zero word, the
+/// length of the path, then the path itself.
+fn module_path_range(rodata: &[u8]) -> Option<Range<usize>> {
+    if rodata.len() < 8 || rodata[0..4] != [0; 4] {
+        return None;
+    }
+    let size = u32::from_le_bytes(rodata[4..8].try_into().unwrap()) as usize;
+    if size == 0 || size > rodata.len() - 8 {
+        return None;
+    }
+    Some(8..8 + size)
+}
+
+/// Locate the dynamic section of a module image through its MOD0 header.
+/// Returns its address and size, including the terminating DT_NULL entry, or
+/// nothing if the image has no MOD0 header.
+fn find_dynamic(image: &[u8], class: ElfClass) -> Result<Option<(u64, u64)>, Error> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        image
+            .get(offset..offset.checked_add(4)?)
+            .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+    };
+    let invalid_dynamic = || Error::InvalidNso {
+        error: "dynamic section is out of bounds",
+        backtrace: Backtrace::generate(),
+    };
+
+    let mod0_offset = match read_u32(4) {
+        Some(offset) => offset as usize,
+        None => return Ok(None),
+    };
+    match image.get(mod0_offset..mod0_offset.saturating_add(4)) {
+        Some(magic) if magic == b"MOD0" => (),
+        _ => return Ok(None),
+    }
+    let relative_offset = read_u32(mod0_offset + 4).ok_or_else(invalid_dynamic)? as i32;
+    let dynamic_offset = (mod0_offset as i64)
+        .checked_add(i64::from(relative_offset))
+        .and_then(|v| usize::try_from(v).ok())
+        .ok_or_else(invalid_dynamic)?;
+
+    // Walk the entries until DT_NULL, whose tag is a zero word.
+    let entry_size = class.dyn_size() as usize;
+    let tag_size = class.word_size() as usize;
+    let mut size = 0;
+    loop {
+        let entry = dynamic_offset
+            .checked_add(size)
+            .and_then(|start| image.get(start..start.checked_add(entry_size)?))
+            .ok_or_else(invalid_dynamic)?;
+        size += entry_size;
+        if entry[..tag_size].iter().all(|&v| v == 0) {
+            break;
+        }
+    }
+    Ok(Some((dynamic_offset as u64, size as u64)))
+}
+
+/// A segment header of a KIP1.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Kip1Segment {
+    pub memory_offset: u32,
+    pub size: u32,
+    pub compressed_size: u32,
+    pub attribute: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Kip1Header {
+    pub name: String,
+    pub title_id: u64,
+    pub process_category: u32,
+    pub main_thread_priority: u8,
+    pub default_cpu_id: u8,
+    pub flags: u8,
+    pub text: Kip1Segment,
+    /// The attribute of the .rodata segment holds the main thread stack size.
+    pub rodata: Kip1Segment,
+    pub data: Kip1Segment,
+    /// Only the memory offset and size of the .bss segment are meaningful.
+    pub bss: Kip1Segment,
+    pub reserved_segments: [Kip1Segment; 2],
+    /// The raw kernel capability descriptors. Unused entries are 0xFFFFFFFF.
+    pub kernel_capabilities: [u32; 0x20],
+}
+
+impl Kip1Header {
+    /// Whether the segment at `index` (text, rodata, data) is BLZ compressed.
+    pub fn is_compressed(&self, index: u32) -> bool {
+        self.flags & (1 << index) != 0
+    }
+
+    pub fn main_thread_stack_size(&self) -> u32 {
+        self.rodata.attribute
+    }
+}
+
+/// An existing KIP1 file. Segments are decompressed when it is read.
+pub struct Kip1File {
+    header: Kip1Header,
+    text: Vec<u8>,
+    rodata: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn read_kip1_segment<R: Read>(f: &mut R) -> io::Result<Kip1Segment> {
+    Ok(Kip1Segment {
+        memory_offset: f.read_u32::<LittleEndian>()?,
+        size: f.read_u32::<LittleEndian>()?,
+        compressed_size: f.read_u32::<LittleEndian>()?,
+        attribute: f.read_u32::<LittleEndian>()?,
+    })
+}
+
+fn read_kip1_segment_data<R: Read + Seek>(
+    f: &mut R,
+    header: &Kip1Header,
+    index: u32,
+    segment: &Kip1Segment,
+) -> Result<Vec<u8>, Error> {
+    // Check the size against what is left of the file before allocating.
+    let position = f.seek(SeekFrom::Current(0))?;
+    let remaining = f.seek(SeekFrom::End(0))? - position;
+    f.seek(SeekFrom::Start(position))?;
+    if u64::from(segment.compressed_size) > remaining {
+        return Err(Error::InvalidKip {
+            error: [
+                ".text is out of bounds",
+                ".rodata is out of bounds",
+                ".data is out of bounds",
+            ][index as usize],
+            backtrace: Backtrace::generate(),
+        });
+    }
+
+    let mut data = vec![0; segment.compressed_size as usize];
+    f.read_exact(&mut data)?;
+
+    if header.is_compressed(index) && !data.is_empty() {
+        data = utils::decompress_blz(&mut data).map_err(|_| Error::InvalidKip {
+            error: [
+                "failed to decompress .text",
+                "failed to decompress .rodata",
+                "failed to decompress .data",
+            ][index as usize],
+            backtrace: Backtrace::generate(),
+        })?;
+    }
+
+    if data.len() != segment.size as usize {
+        return Err(Error::InvalidKip {
+            error: [
+                ".text has the wrong size",
+                ".rodata has the wrong size",
+                ".data has the wrong size",
+            ][index as usize],
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(data)
+}
+
+impl Kip1File {
+    pub fn from_reader<R: Read + Seek>(mut f: R) -> Result<Self, Error> {
+        f.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != b"KIP1" {
+            return Err(Error::InvalidKip {
+                error: "magic is wrong",
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        let mut name = [0; 0xC];
+        f.read_exact(&mut name)?;
+        let name = utils::read_fixed_string(&name)?;
+        let title_id = f.read_u64::<LittleEndian>()?;
+        let process_category = f.read_u32::<LittleEndian>()?;
+        let main_thread_priority = f.read_u8()?;
+        let default_cpu_id = f.read_u8()?;
+        let _reserved = f.read_u8()?;
+        let flags = f.read_u8()?;
+
+        let text = read_kip1_segment(&mut f)?;
+        let rodata = read_kip1_segment(&mut f)?;
+        let data = read_kip1_segment(&mut f)?;
+        let bss = read_kip1_segment(&mut f)?;
+        let reserved_segments = [read_kip1_segment(&mut f)?, read_kip1_segment(&mut f)?];
+
+        let mut kernel_capabilities = [0; 0x20];
+        f.read_u32_into::<LittleEndian>(&mut kernel_capabilities)?;
+
+        let header = Kip1Header {
+            name,
+            title_id,
+            process_category,
+            main_thread_priority,
+            default_cpu_id,
+            flags,
+            text,
+            rodata,
+            data,
+            bss,
+            reserved_segments,
+            kernel_capabilities,
+        };
+
+        // The segments directly follow the header, in order.
+        let text = read_kip1_segment_data(&mut f, &header, 0, &header.text)?;
+        let rodata = read_kip1_segment_data(&mut f, &header, 1, &header.rodata)?;
+        let data = read_kip1_segment_data(&mut f, &header, 2, &header.data)?;
+
+        Ok(Kip1File {
+            header,
+            text,
+            rodata,
+            data,
+        })
+    }
+
+    pub fn header(&self) -> &Kip1Header {
+        &self.header
+    }
+
+    /// The decompressed .text segment.
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// The decompressed .rodata segment.
+    pub fn rodata(&self) -> &[u8] {
+        &self.rodata
+    }
+
+    /// The decompressed .data segment.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A bare NRO header, without any segment.
+    fn empty_nro() -> Vec<u8> {
+        let mut nro = vec![0; 0x80];
+        nro[0x10..0x14].copy_from_slice(b"NRO0");
+        nro[0x18..0x1C].copy_from_slice(&0x80u32.to_le_bytes());
+        nro
+    }
+
+    #[test]
+    fn embedded_files_round_trip() {
+        let nro = NroFile::from_reader(Cursor::new(empty_nro())).unwrap();
+        let mut files = BTreeMap::new();
+        files.insert("a.bin".to_string(), NroAsset::from(b"first".to_vec()));
+        files.insert("b.bin".to_string(), NroAsset::from(b"second".to_vec()));
+        let mut assets = NroAssets {
+            nacp: Some(NroAsset::from(NacpFile::default())),
+            files,
+            ..NroAssets::default()
+        };
+        let mut out = Vec::new();
+        nro.write_with_assets(&mut out, &mut assets).unwrap();
+
+        let nro = NroFile::from_reader(Cursor::new(out)).unwrap();
+        let asset_header = nro.asset_header().unwrap();
+        assert_eq!(asset_header.version, 0);
+        assert_eq!(asset_header.nacp.size, 0x4000);
+        let embedded = nro.embedded_files().unwrap();
+        assert_eq!(embedded.len(), 2);
+        assert_eq!(embedded["a.bin"], b"first");
+        assert_eq!(embedded["b.bin"], b"second");
+
+        // Replace one file and remove the other from the existing RomFS.
+        let mut assets = nro.assets().unwrap();
+        assets
+            .files
+            .insert("a.bin".to_string(), NroAsset::from(b"replaced".to_vec()));
+        assert!(assets.remove_embedded_file("b.bin"));
+        assert!(!assets.remove_embedded_file("c.bin"));
+        let mut out = Vec::new();
+        nro.write_with_assets(&mut out, &mut assets).unwrap();
+
+        let nro = NroFile::from_reader(Cursor::new(out)).unwrap();
+        let embedded = nro.embedded_files().unwrap();
+        assert_eq!(embedded.len(), 1);
+        assert_eq!(embedded["a.bin"], b"replaced");
+
+        // Replacing the RomFS, or removing it, keeps the embedded files.
+        let mut romfs = RomFs::empty();
+        romfs.push_data(b"user".to_vec(), "user.bin");
+        let mut assets = nro.assets().unwrap();
+        assets.romfs = Some(NroAsset::from(romfs));
+        let mut out = Vec::new();
+        nro.write_with_assets(&mut out, &mut assets).unwrap();
+        let replaced = NroFile::from_reader(Cursor::new(out)).unwrap();
+        assert_eq!(replaced.embedded_files().unwrap()["a.bin"], b"replaced");
+        let assets = replaced.assets().unwrap();
+        match assets.romfs {
+            Some(NroAsset::RomFs(romfs)) => {
+                assert!(romfs.contains("user.bin"));
+                assert!(!romfs.contains(NRO_EMBEDDED_FILES_DIR));
+            }
+            _ => panic!("expected the RomFS to be parsed"),
+        }
+
+        let mut assets = nro.assets().unwrap();
+        assets.romfs = None;
+        let mut out = Vec::new();
+        nro.write_with_assets(&mut out, &mut assets).unwrap();
+        let removed = NroFile::from_reader(Cursor::new(out)).unwrap();
+        assert_eq!(removed.embedded_files().unwrap().len(), 1);
+
+        // A RomFS with its own embedded directory is refused.
+        let mut romfs = RomFs::empty();
+        romfs.push_data(b"user".to_vec(), "embedded/a.bin");
+        let mut assets = NroAssets {
+            romfs: Some(NroAsset::from(romfs)),
+            ..NroAssets::default()
+        };
+        assert!(nro.write_with_assets(&mut Vec::new(), &mut assets).is_err());
+    }
+
+    #[test]
+    fn nro_asset_version() {
+        let mut nro = empty_nro();
+        nro.extend_from_slice(b"ASET");
+        nro.extend_from_slice(&[0; 4 + 16 * 3]);
+        assert!(NroFile::from_reader(Cursor::new(nro.clone())).is_ok());
+        nro[0x84] = 1;
+        assert!(NroFile::from_reader(Cursor::new(nro)).is_err());
+    }
+
+    /// A module with one page per segment, a module path, a MOD0 header
+    /// pointing to a dynamic section at the start of .data, and one page of
+    /// bss.
+    fn test_nso(is_32_bit: bool) -> NsoFile {
+        let mut text = vec![0; 0x1000];
+        let entrypoint: u32 = if is_32_bit { 0xEA00_0000 } else { 0x1400_0000 };
+        text[0..4].copy_from_slice(&entrypoint.to_le_bytes());
+        text[4..8].copy_from_slice(&8u32.to_le_bytes());
+        text[8..12].copy_from_slice(b"MOD0");
+        text[12..16].copy_from_slice(&(0x2000u32 - 8).to_le_bytes());
+
+        // The module path, an empty .dynstr, and .dynsym with only the null
+        // symbol.
+        let mut rodata = vec![0; 0x1000];
+        rodata[4..8].copy_from_slice(&8u32.to_le_bytes());
+        rodata[8..16].copy_from_slice(b"original");
+        let sym_size = if is_32_bit { 0x10 } else { 0x18 };
+
+        // DT_STRTAB then DT_NULL.
+        let mut data = vec![0; 0x1000];
+        if is_32_bit {
+            data[0..4].copy_from_slice(&5u32.to_le_bytes());
+            data[4..8].copy_from_slice(&0x1000u32.to_le_bytes());
+        } else {
+            data[0..8].copy_from_slice(&5u64.to_le_bytes());
+            data[8..16].copy_from_slice(&0x1000u64.to_le_bytes());
+        }
+
+        let segment = |memory_offset| NsoSegment {
+            memory_offset,
+            size: 0x1000,
+            ..NsoSegment::default()
+        };
+        NsoFile {
+            header: NsoHeader {
+                version: 0,
+                flags: 0,
+                text: segment(0),
+                rodata: segment(0x1000),
+                data: segment(0x2000),
+                module_name_offset: 0,
+                module_name_size: 0x10,
+                bss_size: 0x1000,
+                build_id: [0; 0x20],
+                api_info: NsoRodataExtent::default(),
+                dynstr: NsoRodataExtent {
+                    offset: 0x10,
+                    size: 1,
+                },
+                dynsym: NsoRodataExtent {
+                    offset: 0x18,
+                    size: sym_size,
+                },
+            },
+            module_name: "original".to_string(),
+            text,
+            rodata,
+            data,
+        }
+    }
+
+    /// Load an ELF written by `NsoFile::write_elf`, as if it was named
+    /// `module.elf`.
+    fn nxo_from_elf(elf: &[u8]) -> io::Result<NxoFile> {
+        let dir = std::env::temp_dir().join(format!("linkle-test-{}-{}", process::id(), elf[4]));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("module.elf");
+        std::fs::write(&path, elf)?;
+        let nxo = NxoFile::from_elf(path.to_str().unwrap());
+        std::fs::remove_dir_all(&dir)?;
+        nxo
+    }
+
+    /// Build an NSO from an ELF written by `NsoFile::write_elf`, and read it.
+    fn rebuild_nso(elf: &[u8], options: &NsoOptions) -> io::Result<NsoFile> {
+        let mut out = Vec::new();
+        nxo_from_elf(elf)?.write_nso(&mut out, options)?;
+        Ok(NsoFile::from_reader(Cursor::new(out)).unwrap())
+    }
+
+    #[test]
+    fn nso_elf_round_trip() {
+        for &is_32_bit in [false, true].iter() {
+            let nso = test_nso(is_32_bit);
+            assert_eq!(nso.is_32_bit(), is_32_bit);
+            let mut elf = Vec::new();
+            nso.write_elf(&mut elf).unwrap();
+            assert_eq!(elf[4], if is_32_bit { 1 } else { 2 });
+
+            // Build an NSO back from the ELF, which must give the same ELF.
+            let options = NsoOptions {
+                module_name: Some("original".to_string()),
+                ..NsoOptions::default()
+            };
+            let nso = rebuild_nso(&elf, &options).unwrap();
+            assert_eq!(nso.header.bss_size, 0x1000);
+            assert_eq!(nso.module_name(), "original");
+            let mut round_trip = Vec::new();
+            nso.write_elf(&mut round_trip).unwrap();
+            assert!(round_trip == elf);
+        }
+    }
+
+    #[test]
+    fn nso_elf_bad_dynamic() {
+        let mut nso = test_nso(false);
+        // Point the dynamic section before the start of the image.
+        nso.text[12..16].copy_from_slice(&(-0x10i32).to_le_bytes());
+        assert!(nso.write_elf(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn nso_bad_sizes() {
+        let mut elf = Vec::new();
+        test_nso(false).write_elf(&mut elf).unwrap();
+        let mut nso = Vec::new();
+        nxo_from_elf(&elf)
+            .unwrap()
+            .write_nso(&mut nso, &NsoOptions::default())
+            .unwrap();
+        assert!(NsoFile::from_reader(Cursor::new(nso.clone())).is_ok());
+
+        // .text past the end of the file.
+        let mut bad = nso.clone();
+        bad[0x10..0x14].copy_from_slice(&0x1000_0000u32.to_le_bytes());
+        assert!(NsoFile::from_reader(Cursor::new(bad)).is_err());
+
+        // A decompressed .text size LZ4 can't reach, or that overflows an i32.
+        for &size in [0x1000_0000u32, 0x8000_0000].iter() {
+            let mut bad = nso.clone();
+            bad[0x18..0x1C].copy_from_slice(&size.to_le_bytes());
+            assert!(NsoFile::from_reader(Cursor::new(bad)).is_err());
+        }
+
+        // A memory offset making the module image too big.
+        let mut nso = test_nso(false);
+        nso.header.data.memory_offset = 0xFFFF_0000;
+        assert!(nso.write_elf(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn nso_module_name() {
+        let mut elf = Vec::new();
+        test_nso(false).write_elf(&mut elf).unwrap();
+
+        let options = NsoOptions {
+            module_name: Some("new".to_string()),
+            ..NsoOptions::default()
+        };
+        let nso = rebuild_nso(&elf, &options).unwrap();
+        assert_eq!(nso.module_name(), "new");
+        assert_eq!(nso.header.module_name_offset, 8);
+        assert_eq!(nso.header.module_name_size, 3);
+        assert_eq!(&nso.rodata[0..0x10], b"\0\0\0\0\x03\0\0\0new\0\0\0\0\0");
+
+        // The name defaults to the ELF file name.
+        let nso = rebuild_nso(&elf, &NsoOptions::default()).unwrap();
+        assert_eq!(nso.module_name(), "module");
+        assert_eq!(nso.header.module_name_offset, 8);
+        assert_eq!(nso.header.module_name_size, 6);
+
+        // The name can't grow past the reserved module path.
+        let options = NsoOptions {
+            module_name: Some("too long!".to_string()),
+            ..NsoOptions::default()
+        };
+        assert!(rebuild_nso(&elf, &options).is_err());
+    }
+
+    fn kip_header(process_category: u32, flags: u8) -> Kip1Header {
+        let mut kernel_capabilities = [0xFFFF_FFFF; 0x20];
+        kernel_capabilities[0] = 0x0300_fd87;
+        Kip1Header {
+            name: "test".to_string(),
+            title_id: 0x0100_0000_0000_1234,
+            process_category,
+            main_thread_priority: 44,
+            default_cpu_id: 3,
+            flags,
+            text: Kip1Segment::default(),
+            rodata: Kip1Segment {
+                attribute: 0x4000,
+                ..Kip1Segment::default()
+            },
+            data: Kip1Segment::default(),
+            bss: Kip1Segment::default(),
+            reserved_segments: [Kip1Segment::default(); 2],
+            kernel_capabilities,
+        }
+    }
+
+    #[test]
+    fn kip_npdm_from_kip() {
+        let npdm = KipNpdm::from_kip(&kip_header(1, 0x3F)).unwrap();
+        assert_eq!(npdm.name, "test");
+        assert_eq!(npdm.title_id.0, 0x0100_0000_0000_1234);
+        assert_eq!(npdm.main_thread_stack_size.0, 0x4000);
+        assert_eq!(npdm.main_thread_priority, 44);
+        assert_eq!(npdm.default_cpu_id, 3);
+        assert_eq!(npdm.process_category, ProcessCategory::KernelBuiltin);
+        assert_eq!(npdm.flags.unwrap().bits(), 0x3F);
+        assert_eq!(npdm.kernel_capabilities.len(), 1);
+
+        // Values that can't be written back are rejected rather than truncated.
+        assert!(KipNpdm::from_kip(&kip_header(0x100, 0x3F)).is_err());
+        assert!(KipNpdm::from_kip(&kip_header(0, 0xBF)).is_err());
+    }
+
+    #[test]
+    fn kip_kernel_capabilities_count() {
+        let mut elf = Vec::new();
+        test_nso(false).write_elf(&mut elf).unwrap();
+        let mut nxo = nxo_from_elf(&elf).unwrap();
+
+        let mut npdm = KipNpdm::from_kip(&kip_header(0, 0)).unwrap();
+        npdm.kernel_capabilities = (0..0x20)
+            .map(|_| KernelCapability::IrqPair([1, 2]))
+            .collect();
+        let mut out = Vec::new();
+        nxo.write_kip1(&mut out, &npdm).unwrap();
+        let kip = Kip1File::from_reader(Cursor::new(out)).unwrap();
+        let irq_pair = KernelCapability::IrqPair([1, 2]).encode()[0];
+        assert!(kip
+            .header
+            .kernel_capabilities
+            .iter()
+            .all(|&v| v == irq_pair));
+
+        npdm.kernel_capabilities
+            .push(KernelCapability::IrqPair([1, 2]));
+        assert!(nxo.write_kip1(&mut Vec::new(), &npdm).is_err());
+
+        // So is a stack size that doesn't fit in the header.
+        npdm.kernel_capabilities.pop();
+        npdm.main_thread_stack_size = HexOrNum(1 << 32);
+        assert!(nxo.write_kip1(&mut Vec::new(), &npdm).is_err());
+    }
+
+    #[test]
+    fn kip_segment_out_of_bounds() {
+        let mut elf = Vec::new();
+        test_nso(false).write_elf(&mut elf).unwrap();
+        let mut npdm = KipNpdm::from_kip(&kip_header(0, 0)).unwrap();
+        npdm.kernel_capabilities.clear();
+        let mut kip = Vec::new();
+        nxo_from_elf(&elf)
+            .unwrap()
+            .write_kip1(&mut kip, &npdm)
+            .unwrap();
+        assert!(Kip1File::from_reader(Cursor::new(kip.clone())).is_ok());
+
+        // The compressed size of .text, then of .data, past the end of the file.
+        for &offset in [0x28, 0x48].iter() {
+            let mut bad = kip.clone();
+            bad[offset..offset + 4].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+            assert!(Kip1File::from_reader(Cursor::new(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn kip_flags_bits() {
+        let flags = KipFlags::from_bits(0b0101_0110);
+        assert!(!flags.compress_text);
+        assert!(flags.compress_rodata);
+        assert!(flags.compress_data);
+        assert!(!flags.is_64_bit);
+        assert!(flags.is_64_bit_address_space);
+        assert!(!flags.use_system_pool_partition);
+        assert!(flags.is_immortal);
+        for bits in 0..0x80 {
+            assert_eq!(KipFlags::from_bits(bits).bits(), bits);
+        }
+
+        let flags: KipFlags = serde_json::from_str("63").unwrap();
+        assert_eq!(flags.bits(), 0x3F);
+        let flags: KipFlags = serde_json::from_str(
+            r#"{ "compress_rodata": false, "is_64_bit": true, "is_64_bit_address_space": true, "use_system_pool_partition": false }"#,
+        )
+        .unwrap();
+        assert_eq!(flags.bits(), 0b0001_1101);
+    }
+
+    #[test]
+    fn kip_segment_compression() {
+        let mut compress = true;
+        let segment = compress_kip_segment(vec![0; 0x1000], &mut compress).unwrap();
+        assert!(compress);
+        assert!(segment.len() < 0x1000);
+
+        // Pseudo-random data, that BLZ can't shrink.
+        let mut state = 1u32;
+        let random = (0..0x1000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect::<Vec<u8>>();
+        let segment = compress_kip_segment(random.clone(), &mut compress).unwrap();
+        assert!(!compress);
+        assert_eq!(segment, random);
+
+        let mut compress = false;
+        let segment = compress_kip_segment(vec![0; 0x1000], &mut compress).unwrap();
+        assert!(!compress);
+        assert_eq!(segment.len(), 0x1000);
+    }
+}
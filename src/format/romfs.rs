@@ -1,10 +1,14 @@
 use crate::error::Error;
-use byteorder::{WriteBytesExt, LE};
+use crate::format::pfs0::ReadSeek;
+use crate::utils::{ReadRange, TryClone};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use snafu::Backtrace;
 use snafu::GenerateBacktrace;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, Cursor, Write};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::rc::{Rc, Weak};
@@ -33,9 +37,23 @@ impl RomFsDirEntCtx {
     }
 }
 
+enum RomFsFileSource {
+    HostPath(PathBuf),
+    SubFile(Box<dyn ReadSeek>),
+}
+
+impl fmt::Debug for RomFsFileSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomFsFileSource::HostPath(path) => f.debug_tuple("HostPath").field(path).finish(),
+            RomFsFileSource::SubFile(_) => f.debug_tuple("SubFile").finish(),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct RomFsFileEntCtx {
-    system_path: PathBuf,
+    source: RomFsFileSource,
     name: String,
     entry_offset: u32,
     offset: u64,
@@ -49,6 +67,16 @@ impl RomFsFileEntCtx {
         let parent_borrow = parent.borrow();
         parent_borrow.internal_path() + "/" + &self.name
     }
+
+    fn copy_to(&mut self, to: &mut dyn Write) -> io::Result<u64> {
+        match &mut self.source {
+            RomFsFileSource::HostPath(path) => io::copy(&mut File::open(path)?, to),
+            RomFsFileSource::SubFile(file) => {
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(file, to)
+            }
+        }
+    }
 }
 
 #[repr(C)]
@@ -155,6 +183,90 @@ fn calc_path_hash(parent: u32, path: &str) -> u32 {
     hash
 }
 
+const ROMFS_ENTRY_EMPTY: u32 = 0xFF_FF_FF_FF;
+
+fn join_internal_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn invalid_entry() -> Error {
+    Error::InvalidRomFs {
+        error: "entry is out of bounds",
+        backtrace: Backtrace::generate(),
+    }
+}
+
+fn read_entry_name(table: &[u8], offset: usize, name_size: u32) -> Result<String, Error> {
+    let name = table
+        .get(offset..offset + name_size as usize)
+        .ok_or_else(invalid_entry)?;
+    Ok(String::from_utf8(name.to_vec())?)
+}
+
+/// Names come from untrusted images and end up joined to host paths when
+/// extracting, so they must be a single, regular path component.
+fn check_entry_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(|c| c == '/' || c == '\\') {
+        return Err(Error::InvalidRomFs {
+            error: "entry name is not a valid file name",
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(())
+}
+
+/// Records a table entry as visited, failing if it was already reached, so
+/// that cyclic sibling or child links can't loop forever.
+fn visit_entry(visited: &mut HashSet<u32>, offset: u32) -> Result<(), Error> {
+    if !visited.insert(offset) {
+        return Err(Error::InvalidRomFs {
+            error: "entry is referenced more than once",
+            backtrace: Backtrace::generate(),
+        });
+    }
+    Ok(())
+}
+
+fn read_dir_entry(dir_table: &[u8], offset: u32) -> Result<(RomFsDirEntryHdr, String), Error> {
+    let entry = dir_table
+        .get(offset as usize..)
+        .filter(|v| v.len() >= mem::size_of::<RomFsDirEntryHdr>())
+        .ok_or_else(invalid_entry)?;
+    let mut cursor = Cursor::new(entry);
+    let hdr = RomFsDirEntryHdr {
+        parent: cursor.read_u32::<LE>()?,
+        sibling: cursor.read_u32::<LE>()?,
+        child: cursor.read_u32::<LE>()?,
+        file: cursor.read_u32::<LE>()?,
+        hash: cursor.read_u32::<LE>()?,
+        name_size: cursor.read_u32::<LE>()?,
+    };
+    let name = read_entry_name(entry, mem::size_of::<RomFsDirEntryHdr>(), hdr.name_size)?;
+    Ok((hdr, name))
+}
+
+fn read_file_entry(file_table: &[u8], offset: u32) -> Result<(RomFsFileEntryHdr, String), Error> {
+    let entry = file_table
+        .get(offset as usize..)
+        .filter(|v| v.len() >= mem::size_of::<RomFsFileEntryHdr>())
+        .ok_or_else(invalid_entry)?;
+    let mut cursor = Cursor::new(entry);
+    let hdr = RomFsFileEntryHdr {
+        parent: cursor.read_u32::<LE>()?,
+        sibling: cursor.read_u32::<LE>()?,
+        offset: cursor.read_u64::<LE>()?,
+        size: cursor.read_u64::<LE>()?,
+        hash: cursor.read_u32::<LE>()?,
+        name_size: cursor.read_u32::<LE>()?,
+    };
+    let name = read_entry_name(entry, mem::size_of::<RomFsFileEntryHdr>(), hdr.name_size)?;
+    Ok((hdr, name))
+}
+
 // TODO: why 0x200???
 const ROMFS_FILEPARTITION_OFS: u64 = 0x200;
 
@@ -170,75 +282,187 @@ pub struct RomFs {
 
 #[allow(clippy::len_without_is_empty)]
 impl RomFs {
-    // Internal path
-    pub fn push_file(&mut self, file_path: &Path, internal_path: &str) -> io::Result<()> {
+    fn find_or_create_dir(&mut self, internal_path: &str) -> Rc<RefCell<RomFsDirEntCtx>> {
         let mut parent = self.dirs[0].clone();
 
-        let mut components = internal_path.split('/').peekable();
-        while let Some(component) = components.next() {
-            if components.peek().is_none() {
-                let metadata = file_path.metadata()?;
-                // Handling last component. Add the file.
-                let file_to_add = Rc::new(RefCell::new(RomFsFileEntCtx {
-                    system_path: PathBuf::from(file_path),
+        for component in internal_path.split('/') {
+            // Find the directory, create if it doesn't exist.
+            if component == "" {
+                continue;
+            }
+            let existing = parent
+                .borrow()
+                .child
+                .iter()
+                .find(|v| v.borrow().name == component)
+                .cloned();
+            let new_parent = if let Some(child) = existing {
+                child
+            } else {
+                // system_path is not used outside from_directory. It's okay if it doesn't
+                // point to something "safe" (or to anything at all)
+                let child = Rc::new(RefCell::new(RomFsDirEntCtx {
+                    system_path: PathBuf::from(""),
                     name: String::from(component),
                     entry_offset: 0,
-                    offset: 0,
-                    size: metadata.len(),
                     parent: Rc::downgrade(&parent),
+                    child: vec![],
+                    file: vec![],
                 }));
-                self.files.push(file_to_add.clone());
-                parent.borrow_mut().file.push(file_to_add.clone());
+                self.dirs.push(child.clone());
+                parent.borrow_mut().child.push(child.clone());
                 parent
                     .borrow_mut()
-                    .file
+                    .child
                     .sort_by_key(|v| v.borrow().name.clone());
 
-                self.file_table_size += mem::size_of::<RomFsFileEntryHdr>() as u64
-                    + align64(file_to_add.borrow().name.len() as u64, 4);
-            } else {
-                // Handling a parent component. Find the directory, create if it doesn't exist.
-                if component == "" {
-                    continue;
-                }
-                let new_parent = if let Some(child) = parent
-                    .borrow()
-                    .child
-                    .iter()
-                    .find(|v| v.borrow().name == component)
-                {
-                    child.clone()
-                } else {
-                    // system_path is not used outside from_directory. It's okay if it doesn't
-                    // point to something "safe" (or to anything at all)
-                    let child = Rc::new(RefCell::new(RomFsDirEntCtx {
-                        system_path: PathBuf::from(""),
-                        name: String::from(component),
-                        entry_offset: 0,
-                        parent: Rc::downgrade(&parent),
-                        child: vec![],
-                        file: vec![],
-                    }));
-                    self.dirs.push(child.clone());
-                    parent.borrow_mut().child.push(child.clone());
-                    parent
-                        .borrow_mut()
-                        .child
-                        .sort_by_key(|v| v.borrow().name.clone());
-
-                    self.dir_table_size += mem::size_of::<RomFsDirEntryHdr>() as u64
-                        + align64(child.borrow().name.len() as u64, 4);
-                    child
-                };
-                parent = new_parent;
-            }
+                self.dir_table_size += mem::size_of::<RomFsDirEntryHdr>() as u64
+                    + align64(child.borrow().name.len() as u64, 4);
+                child
+            };
+            parent = new_parent;
         }
+        parent
+    }
+
+    fn push_entry(&mut self, source: RomFsFileSource, size: u64, internal_path: &str) {
+        let (dir_path, name) = match internal_path.rfind('/') {
+            Some(idx) => (&internal_path[..idx], &internal_path[idx + 1..]),
+            None => ("", internal_path),
+        };
+        let parent = self.find_or_create_dir(dir_path);
+
+        let file_to_add = Rc::new(RefCell::new(RomFsFileEntCtx {
+            source,
+            name: String::from(name),
+            entry_offset: 0,
+            offset: 0,
+            size,
+            parent: Rc::downgrade(&parent),
+        }));
+        self.files.push(file_to_add.clone());
+        parent.borrow_mut().file.push(file_to_add.clone());
+        parent
+            .borrow_mut()
+            .file
+            .sort_by_key(|v| v.borrow().name.clone());
+
+        self.file_table_size += mem::size_of::<RomFsFileEntryHdr>() as u64
+            + align64(file_to_add.borrow().name.len() as u64, 4);
+
         self.files.sort_by_key(|v| v.borrow().internal_path());
         self.dirs.sort_by_key(|v| v.borrow().internal_path());
         self.calculate_offsets();
+    }
+
+    // Internal path
+    pub fn push_file(&mut self, file_path: &Path, internal_path: &str) -> io::Result<()> {
+        let metadata = file_path.metadata()?;
+        self.push_entry(
+            RomFsFileSource::HostPath(PathBuf::from(file_path)),
+            metadata.len(),
+            internal_path,
+        );
         Ok(())
     }
 
+    /// Adds a file whose content is read from `reader` at `internal_path`.
+    /// The reader is kept around until the RomFS is written.
+    pub fn push_reader<R: Read + Seek + 'static>(
+        &mut self,
+        mut reader: R,
+        internal_path: &str,
+    ) -> io::Result<()> {
+        let size = reader.seek(SeekFrom::End(0))?;
+        self.push_entry(
+            RomFsFileSource::SubFile(Box::new(reader)),
+            size,
+            internal_path,
+        );
+        Ok(())
+    }
+
+    /// Adds an in-memory file at `internal_path`.
+    pub fn push_data(&mut self, data: Vec<u8>, internal_path: &str) {
+        let size = data.len() as u64;
+        self.push_entry(
+            RomFsFileSource::SubFile(Box::new(Cursor::new(data))),
+            size,
+            internal_path,
+        );
+    }
+
+    /// Removes the file at `internal_path`, returning whether it existed.
+    pub fn remove_file(&mut self, internal_path: &str) -> bool {
+        let pos = self
+            .files
+            .iter()
+            .position(|v| v.borrow().internal_path().trim_start_matches('/') == internal_path);
+        let file = match pos {
+            Some(pos) => self.files.remove(pos),
+            None => return false,
+        };
+        let parent = file.borrow().parent.upgrade().unwrap();
+        parent.borrow_mut().file.retain(|v| !Rc::ptr_eq(v, &file));
+
+        self.file_table_size -= mem::size_of::<RomFsFileEntryHdr>() as u64
+            + align64(file.borrow().name.len() as u64, 4);
+        self.calculate_offsets();
+        true
+    }
+
+    /// Removes the directory at `internal_path` if it is empty, returning
+    /// whether it was removed.
+    pub fn remove_dir(&mut self, internal_path: &str) -> bool {
+        let pos = self.dirs.iter().position(|v| {
+            let dir = v.borrow();
+            dir.name != ""
+                && dir.child.is_empty()
+                && dir.file.is_empty()
+                && dir.internal_path() == internal_path
+        });
+        let dir = match pos {
+            Some(pos) => self.dirs.remove(pos),
+            None => return false,
+        };
+        let parent = dir.borrow().parent.upgrade().unwrap();
+        parent.borrow_mut().child.retain(|v| !Rc::ptr_eq(v, &dir));
+
+        self.dir_table_size -=
+            mem::size_of::<RomFsDirEntryHdr>() as u64 + align64(dir.borrow().name.len() as u64, 4);
+        self.calculate_offsets();
+        true
+    }
+
+    /// Whether a file or a directory exists at `internal_path`.
+    pub fn contains(&self, internal_path: &str) -> bool {
+        self.dirs
+            .iter()
+            .any(|v| v.borrow().internal_path() == internal_path)
+            || self
+                .files
+                .iter()
+                .any(|v| v.borrow().internal_path().trim_start_matches('/') == internal_path)
+    }
+
+    /// Reads the content of the files directly inside the directory at
+    /// `internal_path`, by name. A missing directory has no files.
+    pub fn read_dir_files(&self, internal_path: &str) -> io::Result<BTreeMap<String, Vec<u8>>> {
+        let mut files = BTreeMap::new();
+        let dir = self
+            .dirs
+            .iter()
+            .find(|v| v.borrow().internal_path() == internal_path);
+        if let Some(dir) = dir {
+            for file in dir.borrow().file.iter() {
+                let mut data = Vec::new();
+                file.borrow_mut().copy_to(&mut data)?;
+                files.insert(file.borrow().name.clone(), data);
+            }
+        }
+        Ok(files)
+    }
+
     pub fn empty() -> RomFs {
         // First, let's create our root folder.
         let root_folder = RomFsDirEntCtx::new_root();
@@ -299,7 +523,7 @@ impl RomFs {
                         + align64(new_dir.borrow().name.len() as u64, 4);
                 } else if file_type.is_file() {
                     let file = Rc::new(RefCell::new(RomFsFileEntCtx {
-                        system_path: entry.path(),
+                        source: RomFsFileSource::HostPath(entry.path()),
                         name: entry
                             .path()
                             .file_name()
@@ -349,11 +573,113 @@ impl RomFs {
         Ok(ctx)
     }
 
+    pub fn from_reader<R: Read + Seek + TryClone + 'static>(mut f: R) -> Result<RomFs, Error> {
+        f.seek(SeekFrom::Start(0))?;
+        let header_size = f.read_u64::<LE>()?;
+        if header_size != 80 {
+            return Err(Error::InvalidRomFs {
+                error: "header size is wrong",
+                backtrace: Backtrace::generate(),
+            });
+        }
+        let _dir_hash_table_ofs = f.read_u64::<LE>()?;
+        let _dir_hash_table_size = f.read_u64::<LE>()?;
+        let dir_table_ofs = f.read_u64::<LE>()?;
+        let dir_table_size = f.read_u64::<LE>()?;
+        let _file_hash_table_ofs = f.read_u64::<LE>()?;
+        let _file_hash_table_size = f.read_u64::<LE>()?;
+        let file_table_ofs = f.read_u64::<LE>()?;
+        let file_table_size = f.read_u64::<LE>()?;
+        let file_partition_ofs = f.read_u64::<LE>()?;
+
+        // Don't trust the header sizes before allocating the tables.
+        let len = f.seek(SeekFrom::End(0))?;
+        let in_bounds = |ofs: u64, size: u64| ofs.checked_add(size).map_or(false, |end| end <= len);
+        if !in_bounds(dir_table_ofs, dir_table_size) || !in_bounds(file_table_ofs, file_table_size)
+        {
+            return Err(Error::InvalidRomFs {
+                error: "tables are out of bounds",
+                backtrace: Backtrace::generate(),
+            });
+        }
+
+        let mut dir_table = vec![0; dir_table_size as usize];
+        f.seek(SeekFrom::Start(dir_table_ofs))?;
+        f.read_exact(&mut dir_table)?;
+        let mut file_table = vec![0; file_table_size as usize];
+        f.seek(SeekFrom::Start(file_table_ofs))?;
+        f.read_exact(&mut file_table)?;
+
+        let mut ctx = RomFs::empty();
+
+        // Same as in from_directory, walk the directory graph with a stack
+        // instead of recursing. The root directory is always the first entry.
+        let mut dirs = vec![(0, String::new())];
+        let mut visited_dirs = HashSet::new();
+        let mut visited_files = HashSet::new();
+        visit_entry(&mut visited_dirs, 0)?;
+        while let Some((dir_offset, dir_path)) = dirs.pop() {
+            let (dir, _) = read_dir_entry(&dir_table, dir_offset)?;
+            ctx.find_or_create_dir(&dir_path);
+
+            let mut child_offset = dir.child;
+            while child_offset != ROMFS_ENTRY_EMPTY {
+                visit_entry(&mut visited_dirs, child_offset)?;
+                let (child, name) = read_dir_entry(&dir_table, child_offset)?;
+                check_entry_name(&name)?;
+                dirs.push((child_offset, join_internal_path(&dir_path, &name)));
+                child_offset = child.sibling;
+            }
+
+            let mut file_offset = dir.file;
+            while file_offset != ROMFS_ENTRY_EMPTY {
+                visit_entry(&mut visited_files, file_offset)?;
+                let (file, name) = read_file_entry(&file_table, file_offset)?;
+                check_entry_name(&name)?;
+                let data_offset = file_partition_ofs
+                    .checked_add(file.offset)
+                    .ok_or_else(invalid_entry)?;
+                let data = ReadRange::new(f.try_clone()?, data_offset, file.size);
+                ctx.push_entry(
+                    RomFsFileSource::SubFile(Box::new(data)),
+                    file.size,
+                    &join_internal_path(&dir_path, &name),
+                );
+                file_offset = file.sibling;
+            }
+        }
+
+        ctx.dirs.sort_by_key(|v| v.borrow().internal_path());
+        ctx.calculate_offsets();
+
+        Ok(ctx)
+    }
+
+    /// Extract every directory and file of this RomFs into `path`.
+    pub fn extract_to(&self, path: &Path) -> Result<(), Error> {
+        for dir in self.dirs.iter() {
+            let dir_path = path.join(dir.borrow().internal_path());
+            fs::create_dir_all(&dir_path).map_err(|err| (err, &dir_path))?;
+        }
+
+        for file in self.files.iter() {
+            let internal_path = file.borrow().internal_path();
+            let internal_path = internal_path.trim_start_matches('/');
+            let file_path = path.join(internal_path);
+            println!("Writing {}", internal_path);
+            let mut out_file = File::create(&file_path).map_err(|err| (err, &file_path))?;
+            file.borrow_mut()
+                .copy_to(&mut out_file)
+                .map_err(|err| (err, &file_path))?;
+        }
+        Ok(())
+    }
+
     pub fn len(&self) -> usize {
         (align64(ROMFS_FILEPARTITION_OFS + self.file_partition_size, 4)
-            + romfs_get_hash_table_count(self.dirs.len() * mem::size_of::<u32>()) as u64
+            + (romfs_get_hash_table_count(self.dirs.len()) * mem::size_of::<u32>()) as u64
             + self.dir_table_size
-            + romfs_get_hash_table_count(self.files.len() * mem::size_of::<u32>()) as u64
+            + (romfs_get_hash_table_count(self.files.len()) * mem::size_of::<u32>()) as u64
             + self.file_table_size) as usize
     }
 
@@ -387,8 +713,6 @@ impl RomFs {
     }
 
     pub fn write(&self, to: &mut dyn Write) -> io::Result<()> {
-        const ROMFS_ENTRY_EMPTY: u32 = 0xFF_FF_FF_FF;
-
         let mut dir_hash_table =
             vec![ROMFS_ENTRY_EMPTY; romfs_get_hash_table_count(self.dirs.len())];
         let mut file_hash_table =
@@ -491,14 +815,27 @@ impl RomFs {
             to.write_all(&vec![0; (new_cur_ofs - cur_ofs) as usize])?;
             cur_ofs = new_cur_ofs;
 
-            println!(
-                "Writing {} to RomFS image...",
-                file.borrow().system_path.to_string_lossy()
-            );
+            match &file.borrow().source {
+                RomFsFileSource::HostPath(path) => {
+                    println!("Writing {} to RomFS image...", path.to_string_lossy())
+                }
+                RomFsFileSource::SubFile(_) => println!(
+                    "Writing {} to RomFS image...",
+                    file.borrow().internal_path().trim_start_matches('/')
+                ),
+            }
             assert_eq!(file.borrow().offset, cur_ofs - 0x200, "Wrong offset");
 
-            let len = io::copy(&mut File::open(&file.borrow().system_path)?, to)?;
-            assert_eq!(len, file.borrow().size, "File changed while building romfs");
+            let len = file.borrow_mut().copy_to(to)?;
+            if len != file.borrow().size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} changed while building romfs",
+                        file.borrow().internal_path()
+                    ),
+                ));
+            }
             cur_ofs += file.borrow().size;
         }
 
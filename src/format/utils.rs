@@ -1,3 +1,4 @@
+use crate::error::Error;
 use serde::de::{Unexpected, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
@@ -21,6 +22,15 @@ pub fn check_string_or_truncate(string: &mut String, name: &str, size: usize) {
     }
 }
 
+/// Reads a NUL-terminated string out of a fixed-size buffer.
+pub fn read_fixed_string(data: &[u8]) -> Result<String, Error> {
+    let len = data
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or_else(|| data.len());
+    Ok(String::from_utf8(data[..len].to_vec())?)
+}
+
 pub fn get_segment_data(
     file: &mut File,
     header: &elf::types::ProgramHeader,
@@ -35,6 +45,14 @@ pub fn compress_lz4(uncompressed_data: &mut Vec<u8>) -> std::io::Result<Vec<u8>>
     lz4::block::compress(&uncompressed_data[..], None, false)
 }
 
+pub fn compress_lz4_hc(uncompressed_data: &mut Vec<u8>) -> std::io::Result<Vec<u8>> {
+    lz4::block::compress(
+        &uncompressed_data[..],
+        Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)),
+        false,
+    )
+}
+
 pub fn compress_blz(uncompressed_data: &mut Vec<u8>) -> blz_nx::BlzResult<Vec<u8>> {
     let mut compressed_data =
         vec![0; blz_nx::get_worst_compression_buffer_size(uncompressed_data.len())];
@@ -43,13 +61,21 @@ pub fn compress_blz(uncompressed_data: &mut Vec<u8>) -> blz_nx::BlzResult<Vec<u8
     Ok(compressed_data)
 }
 
+pub fn decompress_blz(compressed_data: &mut Vec<u8>) -> blz_nx::BlzResult<Vec<u8>> {
+    let mut decompressed_data =
+        vec![0; blz_nx::get_decompression_buffer_size(&compressed_data[..])?];
+    let res = blz_nx::decompress_raw(&mut compressed_data[..], &mut decompressed_data[..])?;
+    decompressed_data.resize(res, 0);
+    Ok(decompressed_data)
+}
+
 pub fn calculate_sha256(data: &[u8]) -> std::io::Result<Vec<u8>> {
     let mut hasher = Sha256::default();
     hasher.update(data);
     Ok(Vec::from(hasher.finalize().as_slice()))
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
 pub struct HexOrNum(pub u64);
 
 impl fmt::Debug for HexOrNum {
@@ -108,3 +134,43 @@ impl Serialize for HexOrNum {
         serializer.collect_str(&format_args!("{:#010x}", self.0))
     }
 }
+
+/// Binary data, serialized as a hex string.
+pub struct HexData(pub Vec<u8>);
+
+impl fmt::Debug for HexData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            f.write_fmt(format_args!("{:02x}", byte))?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for HexData {
+    fn deserialize<D>(deserializer: D) -> Result<HexData, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let invalid =
+            || serde::de::Error::invalid_value(Unexpected::Str(&s), &"a hex-encoded string");
+        if s.len() % 2 != 0 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let data = (0..s.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&s[idx..idx + 2], 16).map_err(|_| invalid()))
+            .collect::<Result<Vec<u8>, D::Error>>()?;
+        Ok(HexData(data))
+    }
+}
+
+impl Serialize for HexData {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&format_args!("{:?}", self))
+    }
+}
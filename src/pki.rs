@@ -648,6 +648,15 @@ impl Keys {
         Ok(keys)
     }
 
+    /// Returns the modulus of the fixed key used to check ACID signatures of
+    /// the given generation.
+    pub fn acid_fixed_key_modulus(&self, generation: usize) -> Option<&[u8]> {
+        match self.acid_fixed_key_modulus.get(generation) {
+            Some(Some(modulus)) => Some(&modulus.0[..]),
+            _ => None,
+        }
+    }
+
     pub fn new_retail(key_path: Option<&Path>) -> Result<Keys, Error> {
         Keys::new(
             key_path,
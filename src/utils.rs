@@ -21,6 +21,12 @@ impl TryClone for std::fs::File {
     }
 }
 
+impl<T: Clone> TryClone for io::Cursor<T> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
 pub struct ReadRange<R> {
     inner: R,
     start_from: u64,
@@ -44,6 +50,17 @@ impl<R> ReadRange<R> {
     }
 }
 
+impl<R: TryClone> TryClone for ReadRange<R> {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(ReadRange {
+            inner: self.inner.try_clone()?,
+            start_from: self.start_from,
+            size: self.size,
+            inner_pos: self.inner_pos,
+        })
+    }
+}
+
 impl<R: io::Read> io::Read for ReadRange<R> {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
         if self.size < self.inner_pos + buf.len() as u64 {